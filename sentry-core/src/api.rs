@@ -1,6 +1,8 @@
-use crate::protocol::{Event, Level};
-use crate::types::Uuid;
+use crate::protocol::{Event, Level, SessionStatus, SessionUpdate};
+use crate::types::{DateTime, Utc, Uuid};
 use crate::{Hub, Integration, IntoBreadcrumbs, Scope};
+#[cfg(feature = "client")]
+use crate::{Session, SessionSnapshot};
 
 /// Captures an event on the currently active client if any.
 ///
@@ -267,6 +269,10 @@ pub fn last_event_id() -> Option<Uuid> {
 /// This is still **experimental** for the moment and is not recommended to be
 /// used with a very high volume of sessions (_request-mode_ sessions).
 ///
+/// When the bound client has no `release` configured, and sessions require
+/// one, starting the session is deferred until a client with a `release` is
+/// bound to the hub.  Ending the session before that cancels it.
+///
 /// # Examples
 ///
 /// ```
@@ -282,6 +288,324 @@ pub fn start_session() {
 }
 
 /// End the current Release Health Session.
-pub fn end_session() {
+///
+/// Returns `true` if a session was active and has been closed by this call.
+/// Calling this again, or after the session has already ended, for example
+/// because it crashed, returns `false`.  This allows nested handlers to avoid
+/// double-closing a session.
+///
+/// # Examples
+///
+/// ```
+/// sentry::start_session();
+///
+/// if !sentry::end_session() {
+///     // the session was already ended, for example by a nested handler.
+/// }
+/// ```
+pub fn end_session() -> bool {
     Hub::with_active(|hub| hub.end_session())
 }
+
+/// End the current Release Health Session with an explicit `status`.
+///
+/// This can be used to distinguish sessions that ended normally, which are
+/// reported as `Exited`, from ones that ended abruptly, for example with
+/// `Abnormal`.  An `Ok` status is reported as `Exited`, as a session can not
+/// end in a non-terminal state.
+///
+/// Like [`end_session`](fn.end_session.html), this returns whether a session
+/// was active and has been closed.
+///
+/// # Examples
+///
+/// ```
+/// use sentry::protocol::SessionStatus;
+///
+/// sentry::start_session();
+///
+/// // the application is shutting down because of a fatal signal
+/// sentry::end_session_with_status(SessionStatus::Abnormal);
+/// ```
+pub fn end_session_with_status(status: SessionStatus) -> bool {
+    Hub::with_active(|hub| hub.end_session_with_status(status))
+}
+
+/// End the current Release Health Session at an explicit end `timestamp`.
+///
+/// This can be used when replaying historical session data, or to reconcile
+/// sessions with external logs.  The session is reported as `Exited`, with
+/// its duration being the time between its start and the `timestamp`.  A
+/// `timestamp` before the start of the session results in a duration of zero.
+///
+/// Like [`end_session`](fn.end_session.html), this returns whether a session
+/// was active and has been closed.
+///
+/// # Examples
+///
+/// ```
+/// use sentry::types::Utc;
+///
+/// sentry::start_session();
+///
+/// // the end of the session was recorded by some external log
+/// let ended = Utc::now();
+/// sentry::end_session_at(ended);
+/// ```
+pub fn end_session_at(timestamp: DateTime<Utc>) -> bool {
+    Hub::with_active(|hub| hub.end_session_at(timestamp))
+}
+
+/// Records that the application entered the background.
+///
+/// This follows the session model of mobile applications, in which a
+/// session spans the time the application is in the foreground.  When the
+/// application returns to the foreground with
+/// [`session_will_enter_foreground`](fn.session_will_enter_foreground.html)
+/// after the `session_background_timeout`, the session is ended as of this
+/// call, and a new session is started.  Returning sooner continues the
+/// current session.
+///
+/// # Examples
+///
+/// ```
+/// sentry::start_session();
+///
+/// // the application is sent to the background...
+/// sentry::session_did_enter_background();
+///
+/// // ...and returns to the foreground shortly after
+/// let new_session = sentry::session_will_enter_foreground();
+/// assert!(!new_session);
+/// ```
+pub fn session_did_enter_background() {
+    Hub::with_active(|hub| hub.session_did_enter_background())
+}
+
+/// Records that the application returns to the foreground.
+///
+/// When the application was in the background for at least the
+/// `session_background_timeout`, the current session is ended as of the time
+/// the application entered the background, and a new session is started.
+///
+/// Returns `true` if a new session was started.  See
+/// [`session_did_enter_background`](fn.session_did_enter_background.html)
+/// for more documentation.
+pub fn session_will_enter_foreground() -> bool {
+    Hub::with_active(|hub| hub.session_will_enter_foreground())
+}
+
+/// Captures a manually created Release Health Session update.
+///
+/// This can be used to report sessions that are tracked outside of the
+/// automatic session lifecycle of the SDK.  The update is queued and sent
+/// together with the other session updates.  The update is not validated,
+/// use [`Client::enqueue_session`](struct.Client.html#method.enqueue_session)
+/// to reject malformed updates instead.
+///
+/// # Examples
+///
+/// ```
+/// use sentry::protocol::{SessionStatus, SessionUpdate};
+///
+/// let session = SessionUpdate::builder("my-game@1.0.0")
+///     .distinct_id("player-1")
+///     .status(SessionStatus::Crashed)
+///     .errors(1)
+///     .duration(123.4)
+///     .build();
+/// sentry::capture_session(session);
+/// ```
+pub fn capture_session(session_update: SessionUpdate<'static>) {
+    Hub::with_active(|hub| hub.capture_session(session_update))
+}
+
+/// Captures multiple manually created Release Health Session updates at once.
+///
+/// This is more efficient than calling [`capture_session`](fn.capture_session.html)
+/// for each update, for example when a framework processes a batch of
+/// requests, as all the updates are queued in a single operation.
+///
+/// # Examples
+///
+/// ```
+/// use sentry::protocol::{SessionStatus, SessionUpdate};
+///
+/// let sessions = (0..10).map(|_| {
+///     SessionUpdate::builder("my-server@1.0.0")
+///         .status(SessionStatus::Exited)
+///         .build()
+/// });
+/// sentry::capture_sessions(sessions);
+/// ```
+pub fn capture_sessions<I>(session_updates: I)
+where
+    I: IntoIterator<Item = SessionUpdate<'static>>,
+{
+    Hub::with_active(|hub| hub.capture_sessions(session_updates))
+}
+
+/// Restarts the background threads of the current client after a `fork()`.
+///
+/// Threads do not survive a `fork()`, which leaves the child process without
+/// a working session flusher.  This should be called in the child process
+/// right after forking, for example in pre-forking servers.  The session
+/// updates that were queued by the parent are not sent again by the child.
+///
+/// See [`Client::reinit_after_fork`](struct.Client.html#method.reinit_after_fork).
+pub fn reinit_after_fork() {
+    with_client_impl! {{
+        Hub::with_active(|hub| {
+            if let Some(client) = hub.client() {
+                client.reinit_after_fork();
+            }
+        })
+    }}
+}
+
+/// Runs the given callback inside of a new Release Health Session.
+///
+/// The session is started before calling `f`, and is ended once it returns.
+/// The session is ended as well when the callback panics, while the panic
+/// itself is propagated to the caller.
+///
+/// Like with [`with_scope`](fn.with_scope.html), the callback runs in a new
+/// scope, so the session of the outer scope is left alone.
+///
+/// # Examples
+///
+/// ```
+/// let result = sentry::with_session(|| {
+///     // capturing any event / error here will update the sessions `errors` count.
+///     42
+/// });
+/// assert_eq!(result, 42);
+/// ```
+pub fn with_session<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    struct SessionGuard;
+
+    impl Drop for SessionGuard {
+        fn drop(&mut self) {
+            end_session();
+        }
+    }
+
+    with_scope(
+        |_| {},
+        || {
+            start_session();
+            let _guard = SessionGuard;
+            f()
+        },
+    )
+}
+
+/// Returns a read-only snapshot of the current Release Health Session, if any.
+///
+/// This can be used to inspect the session, for example to display the
+/// number of errors in a debug overlay.
+///
+/// This function is unavailable if the client implementation is disabled.
+///
+/// # Examples
+///
+/// ```
+/// sentry::start_session();
+///
+/// if let Some(session) = sentry::current_session() {
+///     println!("current session has {} errors", session.errors);
+/// }
+///
+/// sentry::end_session();
+/// ```
+#[cfg(feature = "client")]
+pub fn current_session() -> Option<SessionSnapshot> {
+    Hub::with_active(|hub| hub.current_session())
+}
+
+/// Invokes a function that can modify the current Release Health Session, if any.
+///
+/// This can be used by server frameworks to attach the ip address and user
+/// agent of the client of a request to its session, without going through
+/// the `user` of the scope.  The function is not called when there is no
+/// active session.
+///
+/// The function is called while holding the lock of the session, but not
+/// the lock of the hub, so it can use functions like
+/// [`configure_scope`](fn.configure_scope.html).  It must not use functions
+/// that access the session, like `current_session`, `capture_event` or
+/// `start_session`, as those would deadlock.
+///
+/// This function is unavailable if the client implementation is disabled.
+///
+/// # Examples
+///
+/// ```
+/// sentry::start_session();
+///
+/// sentry::configure_session(|session| {
+///     session.set_ip_address(Some("127.0.0.1".parse().unwrap()));
+///     session.set_user_agent(Some("Mozilla/5.0".into()));
+/// });
+///
+/// sentry::end_session();
+/// ```
+#[cfg(feature = "client")]
+pub fn configure_session<F>(f: F)
+where
+    F: FnOnce(&mut Session),
+{
+    Hub::with_active(|hub| hub.configure_session(f))
+}
+
+/// Marks the current Release Health Session as `Abnormal`.
+///
+/// This can be used to report sessions that had an unexpected abrupt
+/// termination which did not result in a crash, for example when a watchdog
+/// detected a hang.  Once marked abnormal, the session will not receive any
+/// further updates from captured events.
+///
+/// # Examples
+///
+/// ```
+/// sentry::start_session();
+///
+/// // a watchdog detected that the application is unresponsive
+/// sentry::mark_session_abnormal();
+///
+/// sentry::end_session();
+/// ```
+pub fn mark_session_abnormal() {
+    Hub::with_active(|hub| hub.mark_session_abnormal())
+}
+
+/// Sends a heartbeat for the current Release Health Session.
+///
+/// Long-running jobs can use this to periodically report that their session
+/// is still alive, without ending it.  Each heartbeat sends an intermediate
+/// update with status `Ok`.  Sessions that already ended, for example
+/// because they crashed, ignore heartbeats.
+///
+/// Once the current session exceeded the `max_session_duration`, it is ended
+/// as `Exited`, and the heartbeat starts a new session instead.
+///
+/// Returns `true` if a heartbeat was sent for the current session.
+///
+/// # Examples
+///
+/// ```
+/// sentry::start_session();
+///
+/// for _batch in 0..3 {
+///     // processing a batch of a long-running job
+///     sentry::session_heartbeat();
+/// }
+///
+/// sentry::end_session();
+/// ```
+pub fn session_heartbeat() -> bool {
+    Hub::with_active(|hub| hub.session_heartbeat())
+}
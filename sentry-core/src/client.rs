@@ -4,16 +4,19 @@ use std::fmt;
 use std::panic::RefUnwindSafe;
 use std::sync::Arc;
 use std::sync::RwLock;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use rand::random;
 use sentry_types::protocol::v7::SessionUpdate;
 
 use crate::constants::SDK_INFO;
-use crate::protocol::{ClientSdkInfo, Event};
-use crate::session::SessionFlusher;
+use crate::protocol::{ClientSdkInfo, Event, Transaction};
+use crate::session::{
+    check_session_options, sanitize_duration, validate_session_update, SessionFlushMetrics,
+    SessionFlusher, SessionFlusherStats, SessionUpdateError,
+};
 use crate::types::{Dsn, Uuid};
-use crate::{ClientOptions, Envelope, Hub, Integration, Scope, Transport};
+use crate::{ClientOptions, Envelope, Hub, Integration, Scope, SessionMode, Transport};
 
 impl<T: Into<ClientOptions>> From<T> for Client {
     fn from(o: T) -> Client {
@@ -23,6 +26,17 @@ impl<T: Into<ClientOptions>> From<T> for Client {
 
 pub(crate) type TransportArc = Arc<RwLock<Option<Arc<dyn Transport>>>>;
 
+/// The result of capturing an event with
+/// [`Client::capture_event_with_session`](struct.Client.html#method.capture_event_with_session).
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct CapturedEvent {
+    /// The id of the event, or the nil id if it was not sent.
+    pub event_id: Uuid,
+    /// Whether the event counted as an error of the current session, or crashed it.
+    pub session_affected: bool,
+}
+
 /// The Sentry Client.
 ///
 /// The Client is responsible for event processing and sending events to the
@@ -43,6 +57,7 @@ pub(crate) type TransportArc = Arc<RwLock<Option<Arc<dyn Transport>>>>;
 pub struct Client {
     options: ClientOptions,
     transport: TransportArc,
+    session_transport: Option<TransportArc>,
     session_flusher: SessionFlusher,
     integrations: Vec<(TypeId, Arc<dyn Integration>)>,
     sdk_info: ClientSdkInfo,
@@ -60,10 +75,21 @@ impl fmt::Debug for Client {
 impl Clone for Client {
     fn clone(&self) -> Client {
         let transport = Arc::new(RwLock::new(self.transport.read().unwrap().clone()));
-        let session_flusher = SessionFlusher::new(transport.clone());
+        let session_transport = self.session_transport.as_ref().map(|session_transport| {
+            Arc::new(RwLock::new(session_transport.read().unwrap().clone()))
+        });
+        let session_flusher = SessionFlusher::builder(
+            session_transport
+                .clone()
+                .unwrap_or_else(|| transport.clone()),
+        )
+        .options(&self.options)
+        .event_transport(transport.clone())
+        .build();
         Client {
             options: self.options.clone(),
             transport,
+            session_transport,
             session_flusher,
             integrations: self.integrations.clone(),
             sdk_info: self.sdk_info.clone(),
@@ -112,6 +138,13 @@ impl Client {
         };
 
         let transport = Arc::new(RwLock::new(create_transport()));
+        let session_transport = options.session_transport.as_ref().map(|factory| {
+            let session_transport = options
+                .dsn
+                .as_ref()
+                .map(|_| factory.create_transport(&options));
+            Arc::new(RwLock::new(session_transport))
+        });
 
         let mut sdk_info = SDK_INFO.clone();
 
@@ -127,11 +160,33 @@ impl Client {
             integration.setup(&mut options);
             sdk_info.integrations.push(integration.name().to_string());
         }
+        check_session_options(&options);
+        if options.session_envelope_compression {
+            let session_transport = session_transport.as_ref().unwrap_or(&transport);
+            if let Some(ref session_transport) = *session_transport.read().unwrap() {
+                if !session_transport.compresses_envelopes() {
+                    sentry_debug_if!(
+                        options.debug,
+                        "session_envelope_compression is enabled, but the transport \
+                         does not compress envelopes"
+                    );
+                }
+            }
+        }
 
-        let session_flusher = SessionFlusher::new(transport.clone());
+        let session_flusher = SessionFlusher::builder(
+            session_transport
+                .clone()
+                .unwrap_or_else(|| transport.clone()),
+        )
+        .options(&options)
+        .event_transport(transport.clone())
+        .build();
+        session_flusher.replay_spooled();
         Client {
             options,
             transport,
+            session_transport,
             session_flusher,
             integrations,
             sdk_info,
@@ -152,19 +207,12 @@ impl Client {
         mut event: Event<'static>,
         scope: Option<&Scope>,
     ) -> Option<Event<'static>> {
-        if let Some(scope) = scope {
-            scope.update_session_from_event(&event);
-        }
-
         if !self.sample_should_send() {
             return None;
         }
 
-        // event_id and sdk_info are set before the processors run so that the
+        // sdk_info is set before the processors run so that the
         // processors can poke around in that data.
-        if event.event_id.is_nil() {
-            event.event_id = Uuid::new_v4();
-        }
 
         if event.sdk.is_none() {
             // NOTE: we need to clone here because `Event` must be `'static`
@@ -253,34 +301,257 @@ impl Client {
 
     /// Captures an event and sends it to sentry.
     pub fn capture_event(&self, event: Event<'static>, scope: Option<&Scope>) -> Uuid {
+        self.capture_event_with_session(event, scope).event_id
+    }
+
+    /// Captures an event and reports whether it affected the session of the `scope`.
+    ///
+    /// This works like [`capture_event`](#method.capture_event), but also
+    /// tells whether the event counted as an error of the current session,
+    /// or crashed it, so that middleware can record this.  The session is
+    /// updated even when the event itself is sampled out or dropped.
+    pub fn capture_event_with_session(
+        &self,
+        mut event: Event<'static>,
+        scope: Option<&Scope>,
+    ) -> CapturedEvent {
+        let mut captured = CapturedEvent {
+            event_id: Uuid::nil(),
+            session_affected: false,
+        };
         if let Some(ref transport) = *self.transport.read().unwrap() {
+            // the event_id is set before updating the session, so that a crashed
+            // session can refer to the event that crashed it.
+            if event.event_id.is_nil() {
+                event.event_id = Uuid::new_v4();
+            }
+            if let Some(scope) = scope {
+                captured.session_affected = scope.update_session_from_event(&event);
+            }
             if let Some(event) = self.prepare_event(event, scope) {
-                let event_id = event.event_id;
+                captured.event_id = event.event_id;
                 let mut envelope: Envelope = event.into();
-                let session_item = scope.and_then(|scope| {
-                    scope
-                        .session
-                        .lock()
-                        .unwrap()
-                        .as_mut()
-                        .and_then(|session| session.create_envelope_item())
-                });
-                if let Some(session_item) = session_item {
-                    envelope.add_item(session_item);
-                }
+                self.add_session_item(&mut envelope, scope);
                 transport.send_envelope(envelope);
-                return event_id;
             }
         }
+        captured
+    }
+
+    /// Captures a transaction and sends it to sentry.
+    ///
+    /// A failed transaction is counted as an error of the session of the
+    /// given `scope`.
+    pub fn capture_transaction(
+        &self,
+        mut transaction: Transaction<'static>,
+        scope: Option<&Scope>,
+    ) -> Uuid {
+        if let Some(ref transport) = *self.transport.read().unwrap() {
+            if let Some(scope) = scope {
+                scope.update_session_from_transaction(&transaction);
+            }
+            if !self.sample_should_send() {
+                return Default::default();
+            }
+            if transaction.event_id.is_nil() {
+                transaction.event_id = Uuid::new_v4();
+            }
+            if transaction.sdk.is_none() {
+                transaction.sdk = Some(Cow::Owned(self.sdk_info.clone()));
+            }
+            let event_id = transaction.event_id;
+            let mut envelope: Envelope = transaction.into();
+            self.add_session_item(&mut envelope, scope);
+            transport.send_envelope(envelope);
+            return event_id;
+        }
         Default::default()
     }
 
-    pub(crate) fn enqueue_session(&self, session_update: SessionUpdate<'static>) {
-        self.session_flusher.enqueue(session_update)
+    /// Sanitizes a session update before it is queued.
+    ///
+    /// Queued updates go through `before_send_session` when they are flushed.
+    fn prepare_session_update(
+        &self,
+        mut session_update: SessionUpdate<'static>,
+    ) -> SessionUpdate<'static> {
+        // manually created or modified updates might carry an invalid duration
+        session_update.duration = session_update.duration.map(sanitize_duration);
+        session_update
     }
 
-    pub(crate) fn capture_envelope(&self, envelope: Envelope) {
-        if let Some(ref transport) = *self.transport.read().unwrap() {
+    /// Passes a session update that is sent right away through `before_send_session`.
+    pub(crate) fn before_send_session(
+        &self,
+        session_update: SessionUpdate<'static>,
+    ) -> Option<SessionUpdate<'static>> {
+        self.session_flusher
+            .before_send(self.prepare_session_update(session_update))
+    }
+
+    /// Validates and enqueues a manually created session update.
+    ///
+    /// This is the entry point for sessions that are tracked outside of the
+    /// SDK.  Updates with an empty `release`, an invalid `duration`, or more
+    /// `errors` than `max_session_errors` are rejected, instead of being sent
+    /// to the server.  Valid updates are sent together with the other session
+    /// updates, and go through `before_send_session` when they are flushed.  Updates are also
+    /// rejected when sessions are disabled, when there is no transport to
+    /// send them, or when the queue is full with the `DropNewest` policy.
+    pub fn enqueue_session(
+        &self,
+        session_update: SessionUpdate<'static>,
+    ) -> Result<(), SessionUpdateError> {
+        if self.options.session_mode == SessionMode::Disabled {
+            return Err(SessionUpdateError::Disabled);
+        }
+        validate_session_update(&session_update, self.options.max_session_errors)?;
+        if !self.session_flusher.has_transport() {
+            return Err(SessionUpdateError::TransportUnavailable);
+        }
+        if !self.queue_session_update(session_update) {
+            return Err(SessionUpdateError::QueueFull);
+        }
+        Ok(())
+    }
+
+    /// Queues a session update, returning `false` if it was dropped because the queue is full.
+    pub(crate) fn queue_session_update(&self, session_update: SessionUpdate<'static>) -> bool {
+        self.session_flusher
+            .enqueue(self.prepare_session_update(session_update))
+    }
+
+    pub(crate) fn queue_session_updates(&self, session_updates: Vec<SessionUpdate<'static>>) {
+        let session_updates = session_updates
+            .into_iter()
+            .map(|session_update| self.prepare_session_update(session_update))
+            .collect();
+        self.session_flusher.enqueue_many(session_updates)
+    }
+
+    pub(crate) fn track_session(&self, session_update: &SessionUpdate<'static>, started: Instant) {
+        self.session_flusher.track(session_update, started)
+    }
+
+    pub(crate) fn untrack_session(&self, session_id: &Uuid) {
+        self.session_flusher.untrack(session_id)
+    }
+
+    /// Immediately sends all queued session updates to the transport.
+    ///
+    /// Session updates are usually batched and sent periodically in the
+    /// background.  This can be used to deterministically flush them, for
+    /// example in tests or before a graceful shutdown.
+    ///
+    /// Returns `true` if any session updates were sent.
+    pub fn flush_sessions(&self) -> bool {
+        self.session_flusher.flush_now()
+    }
+
+    /// Immediately sends all queued session updates, and releases the queue.
+    ///
+    /// This is meant to be called by external memory-pressure handlers, for
+    /// example from a cgroup monitor, to drain a large pending queue.  It is
+    /// advisory: the queue fills up again with new session updates, and
+    /// updates that the transport can not accept right now are dropped
+    /// instead of being retried.
+    ///
+    /// Returns `true` if any session updates were sent.
+    pub fn flush_sessions_on_pressure(&self) -> bool {
+        self.session_flusher.flush_on_pressure()
+    }
+
+    /// Restarts the background threads of the client after a `fork()`.
+    ///
+    /// This needs to be called in the child process right after forking.  The
+    /// session updates that were queued by the parent process are discarded
+    /// in the child, so they are not sent twice.  The child also stops
+    /// flushing its sessions when the client is dropped, as with
+    /// `session_flush_on_drop` disabled.  Explicitly closing the client still
+    /// flushes them.
+    pub fn reinit_after_fork(&self) {
+        self.session_flusher.reinit_after_fork();
+    }
+
+    /// Sets whether queued session updates are flushed when the client is dropped.
+    ///
+    /// This overrides the `session_flush_on_drop` option at runtime.
+    pub fn set_session_flush_on_drop(&self, flush_on_drop: bool) {
+        self.session_flusher.set_flush_on_drop(flush_on_drop);
+    }
+
+    /// Returns statistics about the session updates sent by this client.
+    ///
+    /// This includes how many session updates were sent to the transport, and
+    /// how many were dropped because the transport was unavailable.
+    pub fn session_stats(&self) -> SessionFlusherStats {
+        self.session_flusher.stats()
+    }
+
+    /// Returns the number of session updates that are queued for sending.
+    ///
+    /// This can be exposed in health checks, to alert when session updates
+    /// pile up, for example because the transport keeps failing.
+    pub fn pending_session_updates(&self) -> usize {
+        self.session_flusher.pending_len().0
+    }
+
+    /// Returns metrics about the most recent flushes of session updates, oldest first.
+    ///
+    /// This includes how long each flush took, and how many session updates
+    /// were flushed, which can help with tuning the `session_flush_interval`.
+    pub fn recent_session_flush_metrics(&self) -> Vec<SessionFlushMetrics> {
+        self.session_flusher.recent_flush_metrics()
+    }
+
+    /// Returns whether this client tracks Release Health Sessions.
+    ///
+    /// This is `false` when sessions are disabled via `SessionMode::Disabled`,
+    /// when a `release` is required but not configured, or when the
+    /// `session_sample_rate` drops all sessions.  Integrations can use this to
+    /// skip their session bookkeeping entirely.
+    pub fn is_session_enabled(&self) -> bool {
+        let options = &self.options;
+        options.session_mode != SessionMode::Disabled
+            && (options.release.is_some() || !options.require_release_for_sessions)
+            && options.session_sample_rate > 0.0
+    }
+
+    /// Adds the pending update of the session of `scope` to the `envelope`.
+    ///
+    /// With a dedicated `session_transport`, the update is sent on its own
+    /// instead.
+    fn add_session_item(&self, envelope: &mut Envelope, scope: Option<&Scope>) {
+        let session_item = scope.and_then(|scope| {
+            scope
+                .session
+                .lock()
+                .unwrap()
+                .as_mut()
+                .and_then(|session| session.create_envelope_item())
+        });
+        if let Some(session_item) = session_item {
+            if self.options.session_dry_run {
+                sentry_debug!("session dry run; not sending session update");
+            } else if self.session_transport.is_some() {
+                let mut session_envelope = Envelope::new();
+                session_envelope.add_item(session_item);
+                self.send_session_envelope(session_envelope);
+            } else {
+                envelope.add_item(session_item);
+            }
+        }
+    }
+
+    /// Sends an envelope of session updates, using the `session_transport` if configured.
+    pub(crate) fn send_session_envelope(&self, envelope: Envelope) {
+        if self.options.session_dry_run {
+            sentry_debug!("session dry run; not sending session envelope");
+            return;
+        }
+        let transport = self.session_transport.as_ref().unwrap_or(&self.transport);
+        if let Some(ref transport) = *transport.read().unwrap() {
             transport.send_envelope(envelope);
         }
     }
@@ -288,28 +559,54 @@ impl Client {
     /// Drains all pending events and shuts down the transport behind the
     /// client.  After shutting down the transport is removed.
     ///
+    /// Queued session updates are flushed to the transport before it is shut
+    /// down.  Sessions that were started but never closed are reported as
+    /// `Abnormal`.
+    ///
     /// This returns `true` if the queue was successfully drained in the
     /// given time or `false` if not (for instance because of a timeout).
     /// If no timeout is provided the client will wait for as long a
     /// `shutdown_timeout` in the client options.
     pub fn close(&self, timeout: Option<Duration>) -> bool {
+        let timeout = timeout.unwrap_or(self.options.shutdown_timeout);
+        let started = Instant::now();
+        // flush all the queued sessions while the transport is still there
+        let mut sessions_flushed = self.session_flusher.shutdown(timeout);
+        let session_transport = self
+            .session_transport
+            .as_ref()
+            .and_then(|session_transport| session_transport.write().unwrap().take());
+        if let Some(session_transport) = session_transport {
+            sentry_debug!("client close; request session transport to shut down");
+            let remaining = timeout.checked_sub(started.elapsed()).unwrap_or_default();
+            sessions_flushed &= session_transport.shutdown(remaining);
+        }
         let transport_opt = self.transport.write().unwrap().take();
         if let Some(transport) = transport_opt {
             sentry_debug!("client close; request transport to shut down");
-            transport.shutdown(timeout.unwrap_or(self.options.shutdown_timeout))
+            let remaining = timeout.checked_sub(started.elapsed()).unwrap_or_default();
+            transport.shutdown(remaining) && sessions_flushed
         } else {
             sentry_debug!("client close; no transport to shut down");
-            true
+            sessions_flushed
         }
     }
 
     fn sample_should_send(&self) -> bool {
-        let rate = self.options.sample_rate;
-        if rate >= 1.0 {
-            true
-        } else {
-            random::<f32>() <= rate
-        }
+        should_send(self.options.sample_rate)
+    }
+
+    /// Decides whether a new session should be tracked, based on the `session_sample_rate`.
+    pub(crate) fn sample_session(&self) -> bool {
+        should_send(self.options.session_sample_rate)
+    }
+}
+
+fn should_send(rate: f32) -> bool {
+    if rate >= 1.0 {
+        true
+    } else {
+        random::<f32>() <= rate
     }
 }
 
@@ -1,16 +1,72 @@
 use std::borrow::Cow;
 use std::fmt;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 use crate::constants::USER_AGENT;
-use crate::protocol::{Breadcrumb, Event};
+use crate::protocol::{Breadcrumb, Event, Level, SessionUpdate};
 use crate::types::Dsn;
-use crate::{Integration, IntoDsn, TransportFactory};
+use crate::{Clock, Integration, IntoDsn, TransportFactory};
 
 /// Type alias for before event/breadcrumb handlers.
 pub type BeforeCallback<T> = Arc<dyn Fn(T) -> Option<T> + Send + Sync>;
 
+/// Type alias for a scheduler that drives the flushing of sessions.
+///
+/// The scheduler is given the session flush interval and a callback, which it
+/// should invoke once every interval.
+pub type SessionFlushScheduler = Arc<dyn Fn(Duration, Box<dyn FnMut() + Send>) + Send + Sync>;
+
+/// Type alias for a one-way hash function applied to session distinct ids.
+pub type DistinctIdHasher = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Type alias for a callback that observes session updates.
+pub type SessionCallback = Arc<dyn Fn(&SessionUpdate<'static>) + Send + Sync>;
+
+/// The Session Mode of the SDK.
+///
+/// Depending on the use-case, the SDK can be set to two different session modes:
+///
+/// * **Application Mode Sessions**:
+///   This mode should be used for user-attended programs, which typically have
+///   a single long running session that span the applications' lifetime.
+///
+/// * **Disabled**:
+///   No Release Health Sessions are tracked or sent at all, even when
+///   explicitly started via `start_session`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SessionMode {
+    /// Long running application session.
+    #[default]
+    Application,
+    /// Release Health Sessions are turned off completely.
+    Disabled,
+}
+
+/// Which session updates are dropped once the queue reaches `max_queued_sessions`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SessionDropPolicy {
+    /// The oldest queued update is dropped to make room for the new one.
+    DropOldest,
+    /// The new update is dropped.
+    DropNewest,
+}
+
+/// A field of the `User` that can serve as the distinct id of sessions.
+///
+/// The `session_distinct_id_source` option lists these in order of
+/// preference.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DistinctIdSource {
+    /// The `id` of the user.
+    Id,
+    /// The `email` of the user.
+    Email,
+    /// The `username` of the user.
+    Username,
+}
+
 /// Configuration settings for the client.
 ///
 /// These options are explained in more detail in the general
@@ -44,6 +100,11 @@ pub struct ClientOptions {
     pub environment: Option<Cow<'static, str>>,
     /// The sample rate for event submission. (0.0 - 1.0, defaults to 1.0)
     pub sample_rate: f32,
+    /// The sample rate for Release Health Sessions. (0.0 - 1.0, defaults to 1.0)
+    ///
+    /// Sessions that are sampled out are not tracked at all, independently of
+    /// the `sample_rate` of events.
+    pub session_sample_rate: f32,
     /// Maximum number of breadcrumbs. (defaults to 100)
     pub max_breadcrumbs: usize,
     /// Attaches stacktraces to messages.
@@ -66,6 +127,15 @@ pub struct ClientOptions {
     pub before_send: Option<BeforeCallback<Event<'static>>>,
     /// Callback that is executed for each Breadcrumb being added.
     pub before_breadcrumb: Option<BeforeCallback<Breadcrumb>>,
+    /// Callback that is executed for each Release Health Session update before sending.
+    ///
+    /// Queued session updates are passed to the callback when they are
+    /// flushed, after consecutive updates of the same session were coalesced.
+    /// Updates that are re-queued after the transport rejected them are
+    /// passed to it again on the next flush.  Session updates that are sent
+    /// right away, like the ones attached to events, are passed to it when
+    /// they are created.  Returning `None` drops the session update.
+    pub before_send_session: Option<BeforeCallback<SessionUpdate<'static>>>,
     // Transport options
     /// The transport to use.
     ///
@@ -73,6 +143,12 @@ pub struct ClientOptions {
     /// reference and returning a `Transport`, a boxed `Arc<Transport>` or
     /// alternatively the `DefaultTransportFactory`.
     pub transport: Option<Arc<dyn TransportFactory>>,
+    /// An optional dedicated transport for Release Health Sessions.
+    ///
+    /// When set, all session updates are sent via this transport instead of
+    /// the main `transport`, for example to route them to a different ingest
+    /// endpoint with separate rate limits.
+    pub session_transport: Option<Arc<dyn TransportFactory>>,
     /// An optional HTTP proxy to use.
     ///
     /// This will default to the `http_proxy` environment variable.
@@ -82,6 +158,13 @@ pub struct ClientOptions {
     /// This will default to the `HTTPS_PROXY` environment variable
     /// or `http_proxy` if that one exists.
     pub https_proxy: Option<Cow<'static, str>>,
+    /// Compress envelopes that only consist of session updates. (defaults to false)
+    ///
+    /// Session envelopes can contain up to 100 session updates, which compress
+    /// very well.  When enabled, the HTTP transports send those envelopes with
+    /// `gzip` content encoding.  Custom transports need to implement this
+    /// themselves, and report it via `Transport::compresses_envelopes`.
+    pub session_envelope_compression: bool,
     /// The timeout on client drop for draining events on shutdown.
     pub shutdown_timeout: Duration,
     // Other options not documented in Unified API
@@ -90,7 +173,208 @@ pub struct ClientOptions {
     /// When automatic session tracking is enabled, a new "user-mode" session
     /// is started at the time of `sentry::init`, and will persist for the
     /// application lifetime.
+    ///
+    /// The session is started on the hub that `sentry::init` binds the client
+    /// to, and is inherited by all scopes pushed on it and hubs created from
+    /// it, instead of starting a new session for each of them.
     pub auto_session_tracking: bool,
+    /// How long the application can be in the background before its session ends. (defaults to 30s)
+    ///
+    /// When the application returns to the foreground after at least this
+    /// long, the session is ended as of the time it entered the background,
+    /// and a new session is started.  See
+    /// [`session_will_enter_foreground`](fn.session_will_enter_foreground.html).
+    pub session_background_timeout: Duration,
+    /// The maximum duration of a single session. (defaults to `None`)
+    ///
+    /// Long-running processes would otherwise report a single session that
+    /// lasts for days.  When an event is captured or a `session_heartbeat` is
+    /// sent after the current session exceeded this duration, the session is
+    /// ended as `Exited`, and a new session is started instead.
+    pub max_session_duration: Option<Duration>,
+    /// Only track Release Health Sessions when a `release` is configured. (defaults to true)
+    ///
+    /// When disabled, sessions will fall back to an `"unknown"` release when
+    /// none is configured, instead of not being tracked at all.
+    pub require_release_for_sessions: bool,
+    /// The interval in which queued session updates are flushed. (defaults to 60s)
+    ///
+    /// Intervals shorter than one second are raised to one second, to avoid
+    /// having the background flusher spin needlessly.
+    pub session_flush_interval: Duration,
+    /// The fraction by which each session flush interval is randomized. (defaults to 0.0)
+    ///
+    /// A value of `0.1` spreads each flush randomly within 10% of the
+    /// `session_flush_interval`, which avoids many processes that were started
+    /// at the same time from flushing in lock-step.  The tradeoff is a less
+    /// predictable delay until sessions are sent.  Values are clamped to the
+    /// range from `0.0` to `1.0`.
+    pub session_flush_jitter: f32,
+    /// The delay after which newly queued session updates are flushed. (defaults to `None`)
+    ///
+    /// By default, the background flusher only wakes up once every
+    /// `session_flush_interval`, so the first session after a quiet period
+    /// waits for a full interval.  With a debounce, the flusher is woken up
+    /// when an update is queued into an empty queue, and flushes all updates
+    /// queued within the debounce delay together.  This has no effect with a
+    /// custom `session_flush_scheduler`.
+    pub session_flush_debounce: Option<Duration>,
+    /// The maximum number of session updates sent in a single envelope. (defaults to 100)
+    ///
+    /// Sentry documents a limit of 100 session items per envelope, which might
+    /// differ for self-hosted installations.
+    pub max_session_items: usize,
+    /// The approximate maximum size in bytes of a single session envelope. (defaults to 1 MiB)
+    ///
+    /// The size of queued session updates is estimated from the length of their
+    /// attributes, and the queue is flushed early once it would exceed this budget.
+    pub max_session_envelope_bytes: usize,
+    /// The maximum number of session updates that are queued for sending. (defaults to `None`)
+    ///
+    /// The queue is usually flushed once it holds `max_session_items`
+    /// updates, but it can keep growing while the transport is failing.  With
+    /// a maximum, updates beyond it are dropped according to the
+    /// `session_queue_drop_policy`, and counted as dropped in the
+    /// [`Client::session_stats`](struct.Client.html#method.session_stats).
+    /// At least one update is always queued.
+    pub max_queued_sessions: Option<usize>,
+    /// Which session updates are dropped once the queue is full. (defaults to `DropOldest`)
+    pub session_queue_drop_policy: SessionDropPolicy,
+    /// How often session updates rejected by the transport are retried before being dropped. (defaults to 3)
+    ///
+    /// Retries happen with an exponential backoff, starting at one second and
+    /// never exceeding the `session_flush_interval`.
+    pub max_session_flush_retries: usize,
+    /// The maximum number of errors counted per session. (defaults to 1000)
+    ///
+    /// Sessions beyond this number of errors are still reported as errored,
+    /// but their `errors` count stops increasing.
+    pub max_session_errors: u64,
+    /// The maximum number of scope tags attached to a session. (defaults to 0)
+    ///
+    /// Tags are taken in the order of their keys when a session starts.  They
+    /// are not part of the documented session protocol, and are sent as they
+    /// are regardless of `send_default_pii`, so only enable this for tags
+    /// that do not contain personal information.
+    pub max_session_tags: usize,
+    /// The minimum level of events that count as session errors. (defaults to `Level::Error`)
+    ///
+    /// Events carrying an exception are counted as errors regardless of
+    /// their level.
+    pub session_error_level_threshold: Level,
+    /// Called once when a session transitions to `Crashed`.
+    ///
+    /// The callback is invoked synchronously while the session is updated
+    /// from the crashing event, before that event is sent.  This can be used
+    /// to capture local diagnostics.  The session is locked during the call,
+    /// so the callback must not start, end or configure sessions itself.
+    pub on_session_crash: Option<SessionCallback>,
+    /// Whether any `Fatal` event crashes the current session. (defaults to `false`)
+    ///
+    /// By default, only events with an explicitly unhandled exception mark a
+    /// session as `Crashed`, while `Fatal` events are counted as errors, like
+    /// any other event at or above the `session_error_level_threshold`.
+    pub session_fatal_is_crash: bool,
+    /// A one-way hash function applied to the distinct id of sessions. (defaults to `None`)
+    ///
+    /// The distinct id is derived from the `user` of the scope, and is used to
+    /// count affected users.  With a hasher, only the hashed id is sent,
+    /// which still allows counting users without transmitting raw user ids.
+    pub session_distinct_id_hasher: Option<DistinctIdHasher>,
+    /// Normalize the distinct id of sessions before it is hashed and sent. (defaults to `false`)
+    ///
+    /// Surrounding whitespace is trimmed, and ids that look like an email
+    /// address are lowercased, so that variants of the same email address
+    /// are counted as a single user.  Other ids keep their case.
+    pub normalize_session_distinct_ids: bool,
+    /// The fields of the `user` that are used as the distinct id of sessions.
+    /// (defaults to `Id`, `Email` and `Username`)
+    ///
+    /// The first of these fields that is set on the user is used.  Fields
+    /// that are not listed are never used, and without any fields, sessions
+    /// carry no distinct id at all.
+    pub session_distinct_id_source: Vec<DistinctIdSource>,
+    /// The clock used for the timestamps and durations of sessions.
+    ///
+    /// Defaults to the [`SystemClock`](struct.SystemClock.html), and can be
+    /// replaced to control time in tests.
+    pub session_clock: Option<Arc<dyn Clock>>,
+    /// Determine how Sessions are being tracked. (defaults to `SessionMode::Application`)
+    pub session_mode: SessionMode,
+    /// The stack size of the background session flusher thread.
+    ///
+    /// Defaults to the stack size of the standard library, which can be
+    /// lowered in constrained environments, as the thread only sends envelopes.
+    pub session_flusher_stack_size: Option<usize>,
+    /// A prefix for the name of the background session flusher thread.
+    ///
+    /// This can be used to tell the threads of multiple clients apart.
+    pub session_flusher_thread_prefix: Option<Cow<'static, str>>,
+    /// A scheduler that flushes sessions instead of a dedicated background thread.
+    ///
+    /// By default, each client spawns its own thread to flush its queued
+    /// session updates.  Applications with many clients can instead run the
+    /// flushing on their own scheduler.  The flush callback does nothing once
+    /// the client is gone, and no retry backoff is applied to it.
+    pub session_flush_scheduler: Option<SessionFlushScheduler>,
+    /// Flush sessions synchronously, without a background thread. (defaults to `false`)
+    ///
+    /// This is meant for environments that can not spawn threads.  Session
+    /// updates are then only sent once `max_session_items` updates are
+    /// queued, when flushed explicitly with `Client::flush_sessions`, and when
+    /// the client is closed.  Whenever the background thread can not be
+    /// spawned, sessions are always flushed synchronously.  Periodic flushes
+    /// can still be driven by a `session_flush_scheduler`.
+    pub synchronous_session_flush: bool,
+    /// Flush the queued session updates when the client is dropped. (defaults to `true`)
+    ///
+    /// In a `fork()`ed child process, the queue still holds the session
+    /// updates of the parent, which would be sent twice by the final flush of
+    /// the child.  [`reinit_after_fork`](fn.reinit_after_fork.html) disables
+    /// this for the child.  It can also be changed at runtime with
+    /// `Client::set_session_flush_on_drop`.  Explicitly closing the client
+    /// still flushes its sessions.
+    pub session_flush_on_drop: bool,
+    /// Track sessions without sending them. (defaults to `false`)
+    ///
+    /// Session updates are still created, passed to `before_send_session`,
+    /// queued and batched into envelopes, but are never handed to the
+    /// transport.  This can be used to validate the session configuration and
+    /// scrubbing in CI or staging environments.  Events are sent as usual.
+    pub session_dry_run: bool,
+    /// A directory in which undeliverable session updates are spooled. (defaults to `None`)
+    ///
+    /// Session updates that the transport keeps rejecting after all retries,
+    /// or that are still queued when the client is dropped, are written to
+    /// this directory instead of being dropped.  They are replayed when the
+    /// next client with the same directory is created, but not when a client
+    /// is cloned.  Each spool file is replayed by only one client, even when
+    /// several processes share the directory.  Spool files that can not be
+    /// read are skipped and removed.
+    ///
+    /// At most 64 spool files are kept, and a client replays at most 1 MiB of
+    /// them.  Replayed updates count towards the `max_queued_sessions`.
+    pub session_spool_dir: Option<PathBuf>,
+    /// Flush all queued session updates when receiving `SIGTERM` or `SIGINT`. (defaults to false)
+    ///
+    /// Processes that are terminated by a signal do not run any destructors,
+    /// so queued session updates would be lost.  When enabled, the signal
+    /// handler only records the signal, and the session flusher thread then
+    /// flushes the queue and re-raises the signal with its default action.
+    /// This replaces the default action of the signals, and other signal
+    /// handlers for them are reset before the signal is re-raised.
+    ///
+    /// With a `synchronous_session_flush`, nothing would handle the recorded
+    /// signals, so the handler is only installed for a flusher thread or a
+    /// `session_flush_scheduler`.  It is uninstalled again when the client
+    /// is closed, which restores the default action of the signals.
+    ///
+    /// Before the signal is re-raised, the transports are shut down within
+    /// the `shutdown_timeout`, so that the flushed envelopes are delivered.
+    ///
+    /// This requires the `session-signals` feature, and is only supported on
+    /// unix.  Otherwise, the option has no effect.
+    pub install_session_signal_handler: bool,
     /// Border frames which indicate a border from a backtrace to
     /// useless internals. Some are automatically included.
     pub extra_border_frames: Vec<&'static str>,
@@ -133,6 +417,27 @@ impl fmt::Debug for ClientOptions {
         struct BeforeBreadcrumb;
         let before_breadcrumb = self.before_breadcrumb.as_ref().map(|_| BeforeBreadcrumb);
         #[derive(Debug)]
+        struct BeforeSendSession;
+        let before_send_session = self.before_send_session.as_ref().map(|_| BeforeSendSession);
+        #[derive(Debug)]
+        struct SessionFlushScheduler;
+        let session_flush_scheduler = self
+            .session_flush_scheduler
+            .as_ref()
+            .map(|_| SessionFlushScheduler);
+        #[derive(Debug)]
+        struct DistinctIdHasher;
+        let session_distinct_id_hasher = self
+            .session_distinct_id_hasher
+            .as_ref()
+            .map(|_| DistinctIdHasher);
+        #[derive(Debug)]
+        struct OnSessionCrash;
+        let on_session_crash = self.on_session_crash.as_ref().map(|_| OnSessionCrash);
+        #[derive(Debug)]
+        struct SessionClock;
+        let session_clock = self.session_clock.as_ref().map(|_| SessionClock);
+        #[derive(Debug)]
         struct TransportFactory;
 
         let integrations: Vec<_> = self.integrations.iter().map(|i| i.name()).collect();
@@ -143,6 +448,7 @@ impl fmt::Debug for ClientOptions {
             .field("release", &self.release)
             .field("environment", &self.environment)
             .field("sample_rate", &self.sample_rate)
+            .field("session_sample_rate", &self.session_sample_rate)
             .field("max_breadcrumbs", &self.max_breadcrumbs)
             .field("attach_stacktrace", &self.attach_stacktrace)
             .field("send_default_pii", &self.send_default_pii)
@@ -153,11 +459,76 @@ impl fmt::Debug for ClientOptions {
             .field("default_integrations", &self.default_integrations)
             .field("before_send", &before_send)
             .field("before_breadcrumb", &before_breadcrumb)
+            .field("before_send_session", &before_send_session)
             .field("transport", &TransportFactory)
+            .field(
+                "session_transport",
+                &self.session_transport.as_ref().map(|_| TransportFactory),
+            )
             .field("http_proxy", &self.http_proxy)
             .field("https_proxy", &self.https_proxy)
+            .field(
+                "session_envelope_compression",
+                &self.session_envelope_compression,
+            )
             .field("shutdown_timeout", &self.shutdown_timeout)
             .field("auto_session_tracking", &self.auto_session_tracking)
+            .field(
+                "session_background_timeout",
+                &self.session_background_timeout,
+            )
+            .field("max_session_duration", &self.max_session_duration)
+            .field(
+                "require_release_for_sessions",
+                &self.require_release_for_sessions,
+            )
+            .field("session_flush_interval", &self.session_flush_interval)
+            .field("session_flush_jitter", &self.session_flush_jitter)
+            .field("session_flush_debounce", &self.session_flush_debounce)
+            .field("max_session_items", &self.max_session_items)
+            .field(
+                "max_session_envelope_bytes",
+                &self.max_session_envelope_bytes,
+            )
+            .field("max_queued_sessions", &self.max_queued_sessions)
+            .field("session_queue_drop_policy", &self.session_queue_drop_policy)
+            .field("max_session_flush_retries", &self.max_session_flush_retries)
+            .field("max_session_errors", &self.max_session_errors)
+            .field("max_session_tags", &self.max_session_tags)
+            .field(
+                "session_error_level_threshold",
+                &self.session_error_level_threshold,
+            )
+            .field("on_session_crash", &on_session_crash)
+            .field("session_fatal_is_crash", &self.session_fatal_is_crash)
+            .field("session_distinct_id_hasher", &session_distinct_id_hasher)
+            .field(
+                "normalize_session_distinct_ids",
+                &self.normalize_session_distinct_ids,
+            )
+            .field(
+                "session_distinct_id_source",
+                &self.session_distinct_id_source,
+            )
+            .field("session_clock", &session_clock)
+            .field("session_mode", &self.session_mode)
+            .field(
+                "session_flusher_stack_size",
+                &self.session_flusher_stack_size,
+            )
+            .field(
+                "session_flusher_thread_prefix",
+                &self.session_flusher_thread_prefix,
+            )
+            .field("session_flush_scheduler", &session_flush_scheduler)
+            .field("synchronous_session_flush", &self.synchronous_session_flush)
+            .field("session_flush_on_drop", &self.session_flush_on_drop)
+            .field("session_dry_run", &self.session_dry_run)
+            .field("session_spool_dir", &self.session_spool_dir)
+            .field(
+                "install_session_signal_handler",
+                &self.install_session_signal_handler,
+            )
             .field("extra_border_frames", &self.extra_border_frames)
             .field("trim_backtraces", &self.trim_backtraces)
             .field("user_agent", &self.user_agent)
@@ -178,6 +549,7 @@ impl Default for ClientOptions {
             release: None,
             environment: Some(env.into()),
             sample_rate: 1.0,
+            session_sample_rate: 1.0,
             max_breadcrumbs: 100,
             attach_stacktrace: false,
             send_default_pii: false,
@@ -188,11 +560,47 @@ impl Default for ClientOptions {
             default_integrations: true,
             before_send: None,
             before_breadcrumb: None,
+            before_send_session: None,
             transport: None,
+            session_transport: None,
             http_proxy: None,
             https_proxy: None,
+            session_envelope_compression: false,
             shutdown_timeout: Duration::from_secs(2),
             auto_session_tracking: false,
+            session_background_timeout: Duration::from_secs(30),
+            max_session_duration: None,
+            require_release_for_sessions: true,
+            session_flush_interval: Duration::from_secs(60),
+            session_flush_jitter: 0.0,
+            session_flush_debounce: None,
+            max_session_items: 100,
+            max_session_envelope_bytes: 1024 * 1024,
+            max_queued_sessions: None,
+            session_queue_drop_policy: SessionDropPolicy::DropOldest,
+            max_session_flush_retries: 3,
+            max_session_errors: 1000,
+            max_session_tags: 0,
+            session_error_level_threshold: Level::Error,
+            on_session_crash: None,
+            session_fatal_is_crash: false,
+            session_distinct_id_hasher: None,
+            normalize_session_distinct_ids: false,
+            session_distinct_id_source: vec![
+                DistinctIdSource::Id,
+                DistinctIdSource::Email,
+                DistinctIdSource::Username,
+            ],
+            session_clock: None,
+            session_mode: SessionMode::Application,
+            session_flusher_stack_size: None,
+            session_flusher_thread_prefix: None,
+            session_flush_scheduler: None,
+            synchronous_session_flush: false,
+            session_flush_on_drop: true,
+            session_dry_run: false,
+            session_spool_dir: None,
+            install_session_signal_handler: false,
             extra_border_frames: vec![],
             trim_backtraces: true,
             user_agent: Cow::Borrowed(&USER_AGENT),
@@ -0,0 +1,35 @@
+use std::time::Instant;
+
+use crate::types::{DateTime, Utc};
+
+/// The trait for clocks.
+///
+/// Release Health Sessions take their start time, timestamps and duration
+/// from a clock.  By default, this is the [`SystemClock`], but a custom
+/// implementation can be set as the `session_clock` option, for example to
+/// test session durations deterministically.
+///
+/// [`SystemClock`]: struct.SystemClock.html
+pub trait Clock: Send + Sync + 'static {
+    /// Returns the current time of the monotonic clock.
+    ///
+    /// Session durations are measured with this clock.
+    fn now_instant(&self) -> Instant;
+
+    /// Returns the current wall clock time.
+    fn now_utc(&self) -> DateTime<Utc>;
+}
+
+/// The clock of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
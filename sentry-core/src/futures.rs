@@ -47,6 +47,63 @@ where
     }
 }
 
+/// A future that tracks a Release Health Session for its execution.
+///
+/// The session is started on the bound `Hub` when the future is created, and
+/// ended once the future completes, or when it is dropped before completion.
+/// As the session lives on the hub, it is correctly attributed no matter on
+/// which thread the executor polls the future.  Users usually do not need to
+/// construct this type manually, but rather use the
+/// [`FutureExt::bind_session`] method instead.
+///
+/// [`FutureExt::bind_session`]: trait.FutureExt.html#method.bind_session
+#[derive(Debug)]
+pub struct SessionFuture<F> {
+    inner: SentryFuture<F>,
+    ended: bool,
+}
+
+impl<F> SessionFuture<F> {
+    /// Creates a new bound future with a `Hub`, and starts a session on it.
+    pub fn new(hub: Arc<Hub>, future: F) -> Self {
+        hub.start_session();
+        Self {
+            inner: SentryFuture::new(hub, future),
+            ended: false,
+        }
+    }
+}
+
+impl<F> Future for SessionFuture<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // https://doc.rust-lang.org/std/pin/index.html#pinning-is-structural-for-field
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+        match inner.poll(cx) {
+            Poll::Ready(output) => {
+                this.inner.hub.end_session();
+                this.ended = true;
+                Poll::Ready(output)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<F> Drop for SessionFuture<F> {
+    fn drop(&mut self) {
+        // the future was cancelled before it completed
+        if !self.ended {
+            self.inner.hub.end_session();
+        }
+    }
+}
+
 /// Future extensions for Sentry.
 pub trait SentryFutureExt: Sized {
     /// Binds a hub to the execution of this future.
@@ -61,14 +118,28 @@ pub trait SentryFutureExt: Sized {
             hub: hub.into(),
         }
     }
+
+    /// Binds a hub to the execution of this future, and tracks a session on it.
+    ///
+    /// The hub should not be shared with other futures, as each hub can only
+    /// have a single active session.  See [`SessionFuture`] for details.
+    ///
+    /// [`SessionFuture`]: struct.SessionFuture.html
+    fn bind_session<H>(self, hub: H) -> SessionFuture<Self>
+    where
+        H: Into<Arc<Hub>>,
+    {
+        SessionFuture::new(hub.into(), self)
+    }
 }
 
 impl<F> SentryFutureExt for F where F: Future {}
 
 #[cfg(all(test, feature = "test"))]
 mod tests {
-    use crate::test::with_captured_events;
-    use crate::{capture_message, configure_scope, Hub, Level, SentryFutureExt};
+    use crate::protocol::{EnvelopeItem, SessionStatus};
+    use crate::test::{with_captured_envelopes_options, with_captured_events};
+    use crate::{capture_message, configure_scope, ClientOptions, Hub, Level, SentryFutureExt};
     use tokio::runtime::Runtime;
 
     #[test]
@@ -104,4 +175,48 @@ mod tests {
         assert_eq!(events[1].transaction, Some("transaction1".into()));
         assert_eq!(events[2].transaction, Some("transaction2".into()));
     }
+
+    #[test]
+    fn test_session_future() {
+        let envelopes = with_captured_envelopes_options(
+            || {
+                let mut runtime = tokio::runtime::Builder::new()
+                    .threaded_scheduler()
+                    .core_threads(2)
+                    .build()
+                    .unwrap();
+
+                runtime.block_on(async {
+                    let task = async {
+                        for _ in 0..10 {
+                            tokio::task::spawn(async {}).await.unwrap();
+                        }
+                        capture_message("oh no", Level::Error);
+                        tokio::task::spawn(async {}).await.unwrap();
+                    }
+                    .bind_session(Hub::new_from_top(Hub::current()));
+                    tokio::task::spawn(task).await.unwrap();
+                });
+            },
+            ClientOptions {
+                release: Some("some-release".into()),
+                ..Default::default()
+            },
+        );
+
+        let sessions: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].session_id, sessions[1].session_id);
+        assert_eq!(sessions[0].errors, 1);
+        assert!(sessions[0].init);
+        assert_eq!(sessions[1].errors, 1);
+        assert_eq!(sessions[1].status, SessionStatus::Exited);
+    }
 }
@@ -8,11 +8,15 @@ use std::sync::{Arc, Mutex, PoisonError, RwLock, TryLockError};
 use std::thread;
 use std::time::Duration;
 
-use crate::protocol::{Breadcrumb, Event, Level, SessionStatus};
-use crate::types::Uuid;
+use crate::protocol::{Breadcrumb, Event, Level, SessionStatus, SessionUpdate, Transaction};
+use crate::types::{DateTime, Utc, Uuid};
 use crate::{event_from_error, Integration, IntoBreadcrumbs, Scope, ScopeGuard};
 #[cfg(feature = "client")]
-use crate::{scope::Stack, session::Session, Client, Envelope};
+use crate::{
+    scope::{Stack, StackLayer},
+    session::{session_clock, Session},
+    CapturedEvent, Client, Envelope, SessionSnapshot,
+};
 
 #[cfg(feature = "client")]
 lazy_static::lazy_static! {
@@ -61,6 +65,57 @@ impl HubImpl {
     }
 }
 
+/// Starts a new session on the `top` layer of the stack, replacing its current one.
+///
+/// The current session is replaced even if no new session is started, for
+/// example because it was sampled out.  Returns the replaced session, which
+/// must be dropped outside of the stack lock, as sending its final update
+/// might need to access the hub.  If the session can not be started until a
+/// client with a `release` is bound, it is deferred until then.
+#[cfg(feature = "client")]
+fn replace_session(top: &mut StackLayer) -> Arc<Mutex<Option<Session>>> {
+    let session = Session::from_stack(top);
+    top.deferred_session = session.is_none() && Session::awaits_release(top);
+    // When creating a *new* session, we make sure it is unique,
+    // as to no inherit *backwards* to any parents.
+    let scope = Arc::make_mut(&mut top.scope);
+    std::mem::replace(&mut scope.session, Arc::new(Mutex::new(session)))
+}
+
+/// Replaces the session of the `top` layer once it exceeded the
+/// `max_session_duration`.
+///
+/// The new session takes the place of the expired one within the same
+/// session lock, so that it is shared by all scopes of the expired session.
+/// It is created without holding that lock, and is discarded if another
+/// thread replaced the expired session in the meantime.  Returns the expired
+/// session, which must be closed and sent outside of the stack lock.
+#[cfg(feature = "client")]
+fn take_expired_session(top: &StackLayer) -> Option<Session> {
+    let max_duration = top.client.as_ref()?.options().max_session_duration?;
+    let expired_id = {
+        let session = top.scope.session.lock().unwrap();
+        let session = session.as_ref()?;
+        if session.elapsed() < max_duration {
+            return None;
+        }
+        session.session_id()
+    };
+    let replacement = Session::from_stack(top);
+    let mut session = top.scope.session.lock().unwrap();
+    match *session {
+        Some(ref current) if current.session_id() == expired_id => {
+            std::mem::replace(&mut *session, replacement)
+        }
+        _ => {
+            if let Some(replacement) = replacement {
+                replacement.discard();
+            }
+            None
+        }
+    }
+}
+
 /// The central object that can manages scopes and clients.
 ///
 /// This can be used to capture events and manage the scope.  This object is
@@ -261,13 +316,45 @@ impl Hub {
     /// See the global [`capture_event`](fn.capture_event.html)
     /// for more documentation.
     pub fn capture_event(&self, event: Event<'static>) -> Uuid {
+        with_client_impl! {{
+            self.capture_event_with_session(event).event_id
+        }}
+    }
+
+    /// Sends the event to the current client with the current scope, and
+    /// reports whether it affected the current session.
+    ///
+    /// See [`Client::capture_event_with_session`](struct.Client.html#method.capture_event_with_session)
+    /// for more documentation.
+    ///
+    /// This method is unavailable if the client implementation is disabled.
+    #[cfg(feature = "client")]
+    pub fn capture_event_with_session(&self, event: Event<'static>) -> CapturedEvent {
+        self.roll_expired_session();
+        self.inner.with(|stack| {
+            let top = stack.top();
+            if let Some(ref client) = top.client {
+                let captured = client.capture_event_with_session(event, Some(&top.scope));
+                *self.last_event_id.write().unwrap() = Some(captured.event_id);
+                captured
+            } else {
+                CapturedEvent {
+                    event_id: Uuid::nil(),
+                    session_affected: false,
+                }
+            }
+        })
+    }
+
+    /// Captures a transaction.
+    ///
+    /// Unlike events, this does not update the last event id of the hub.
+    pub fn capture_transaction(&self, transaction: Transaction<'static>) -> Uuid {
         with_client_impl! {{
             self.inner.with(|stack| {
                 let top = stack.top();
                 if let Some(ref client) = top.client {
-                    let event_id = client.capture_event(event, Some(&top.scope));
-                    *self.last_event_id.write().unwrap() = Some(event_id);
-                    event_id
+                    client.capture_transaction(transaction, Some(&top.scope))
                 } else {
                     Default::default()
                 }
@@ -281,18 +368,15 @@ impl Hub {
     /// for more documentation.
     pub fn capture_message(&self, msg: &str, level: Level) -> Uuid {
         with_client_impl! {{
-            self.inner.with(|stack| {
-                let top = stack.top();
-                if let Some(ref client) = top.client {
-                    let mut event = Event {
-                        message: Some(msg.to_string()),
-                        level,
-                        ..Default::default()
-                    };
-                    self.capture_event(event)
-                } else {
-                    Uuid::nil()
-                }
+            // the event is captured outside of the stack lock, as capturing
+            // it might close and send an expired session
+            if self.inner.with(|stack| stack.top().client.is_none()) {
+                return Uuid::nil();
+            }
+            self.capture_event(Event {
+                message: Some(msg.to_string()),
+                level,
+                ..Default::default()
             })
         }}
     }
@@ -304,11 +388,21 @@ impl Hub {
     }
 
     /// Binds a new client to the hub.
+    ///
+    /// A session that was started before a client with a `release` was bound
+    /// is started now, if the new client has a `release`.
     #[cfg(feature = "client")]
     pub fn bind_client(&self, client: Option<Arc<Client>>) {
-        self.inner.with_mut(|stack| {
-            stack.top_mut().client = client;
-        })
+        let previous = self.inner.with_mut(|stack| {
+            let top = stack.top_mut();
+            top.client = client;
+            if top.deferred_session {
+                Some(replace_session(top))
+            } else {
+                None
+            }
+        });
+        drop(previous);
     }
 
     /// Start a new session for Release Health.
@@ -316,36 +410,273 @@ impl Hub {
     /// See the global [`start_session`](fn.start_session.html)
     /// for more documentation.
     pub fn start_session(&self) {
+        with_client_impl! {{
+            let previous = self.inner.with_mut(|stack| replace_session(stack.top_mut()));
+            // the previous session is dropped outside of the stack lock, as
+            // sending its final update might need to access the hub.
+            drop(previous);
+        }}
+    }
+
+    /// End the current Release Health Session.
+    ///
+    /// See the global [`end_session`](fn.end_session.html)
+    /// for more documentation.
+    pub fn end_session(&self) -> bool {
+        self.end_session_with_status(SessionStatus::Exited)
+    }
+
+    /// End the current Release Health Session with the given `status`.
+    ///
+    /// See the global [`end_session_with_status`](fn.end_session_with_status.html)
+    /// for more documentation.
+    pub fn end_session_with_status(&self, status: SessionStatus) -> bool {
+        with_client_impl! {{
+            self.end_session_with(|session| session.close_with_status(status))
+        }}
+    }
+
+    /// End the current Release Health Session and start a new one.
+    ///
+    /// The current session is closed as `Exited`, and the new session takes
+    /// its place on the same scope within a single operation, so that there is
+    /// no window without an active session.  Long-running servers can use this
+    /// to roll their session periodically, for example every N requests.
+    ///
+    /// Returns `true` if a session was active and has been closed.
+    pub fn end_session_and_start_new(&self) -> bool {
+        with_client_impl! {{
+            let (previous, client) = self.inner.with_mut(|stack| {
+                let top = stack.top_mut();
+                let previous = top.scope.session.lock().unwrap().take();
+                // the emptied session of the scope can be dropped right away
+                replace_session(top);
+                (previous, top.client.clone())
+            });
+            Self::close_and_send_session(previous, client, |session| {
+                session.close_with_status(SessionStatus::Exited)
+            })
+        }}
+    }
+
+    /// Records that the application entered the background.
+    ///
+    /// See the global [`session_did_enter_background`](fn.session_did_enter_background.html)
+    /// for more documentation.
+    pub fn session_did_enter_background(&self) {
         with_client_impl! {{
             self.inner.with_mut(|stack| {
                 let top = stack.top_mut();
-                if let Some(session) = Session::from_stack(top) {
-                    // When creating a *new* session, we make sure it is unique,
-                    // as to no inherit *backwards* to any parents.
-                    let mut scope = Arc::make_mut(&mut top.scope);
-                    scope.session = Arc::new(Mutex::new(Some(session)));
+                if let Some(ref client) = top.client {
+                    let clock = session_clock(client.options());
+                    top.backgrounded = Some((clock.now_instant(), clock.now_utc()));
                 }
             })
         }}
     }
 
-    /// End the current Release Health Session.
+    /// Records that the application returns to the foreground.
     ///
-    /// See the global [`end_session`](fn.end_session.html)
+    /// See the global [`session_will_enter_foreground`](fn.session_will_enter_foreground.html)
     /// for more documentation.
-    pub fn end_session(&self) {
+    pub fn session_will_enter_foreground(&self) -> bool {
         with_client_impl! {{
-            self.inner.with_mut(|stack| {
+            let backgrounded = self.inner.with_mut(|stack| {
                 let top = stack.top_mut();
-                if let Some(mut session) = top.scope.session.lock().unwrap().take() {
-                    session.close();
-                    if let Some(item) = session.create_envelope_item() {
+                let backgrounded = top.backgrounded.take()?;
+                Some((backgrounded, top.client.clone()?))
+            });
+            let ((instant, timestamp), client) = match backgrounded {
+                Some(backgrounded) => backgrounded,
+                None => return false,
+            };
+            let options = client.options();
+            let background = session_clock(options)
+                .now_instant()
+                .saturating_duration_since(instant);
+            if background < options.session_background_timeout {
+                return false;
+            }
+            // the session ended when the application entered the background
+            self.end_session_at(timestamp);
+            self.start_session();
+            true
+        }}
+    }
+
+    /// End the current Release Health Session at the given `timestamp`.
+    ///
+    /// See the global [`end_session_at`](fn.end_session_at.html)
+    /// for more documentation.
+    pub fn end_session_at(&self, timestamp: DateTime<Utc>) -> bool {
+        with_client_impl! {{
+            self.end_session_with(|session| session.close_at(timestamp))
+        }}
+    }
+
+    /// Takes the current session, closes it with `close` and sends its final update.
+    #[cfg(feature = "client")]
+    fn end_session_with<F>(&self, close: F) -> bool
+    where
+        F: FnOnce(&mut Session) -> bool,
+    {
+        let (session, client) = self.inner.with_mut(|stack| {
+            let top = stack.top_mut();
+            top.deferred_session = false;
+            let session = top.scope.session.lock().unwrap().take();
+            (session, top.client.clone())
+        });
+        Self::close_and_send_session(session, client, close)
+    }
+
+    /// Ends the current session as `Exited` and starts a new one, once it
+    /// exceeded the `max_session_duration`.
+    #[cfg(feature = "client")]
+    fn roll_expired_session(&self) {
+        let (expired, client) = self.inner.with(|stack| {
+            let top = stack.top();
+            (take_expired_session(top), top.client.clone())
+        });
+        Self::close_and_send_session(expired, client, |session| {
+            session.close_with_status(SessionStatus::Exited)
+        });
+    }
+
+    /// Closes the `session` with `close` and sends its final update.
+    ///
+    /// The session must be closed outside of the stack lock, as sending its
+    /// final update might need to access the hub.
+    #[cfg(feature = "client")]
+    fn close_and_send_session<F>(
+        session: Option<Session>,
+        client: Option<Arc<Client>>,
+        close: F,
+    ) -> bool
+    where
+        F: FnOnce(&mut Session) -> bool,
+    {
+        let mut session = match session {
+            Some(session) => session,
+            None => return false,
+        };
+        let closed = close(&mut session);
+        if let Some(item) = session.create_envelope_item() {
+            let mut envelope = Envelope::new();
+            envelope.add_item(item);
+            if let Some(ref client) = client {
+                client.send_session_envelope(envelope);
+            }
+        }
+        closed
+    }
+
+    /// Returns a snapshot of the current Release Health Session, if any.
+    ///
+    /// See the global [`current_session`](fn.current_session.html)
+    /// for more documentation.
+    ///
+    /// This method is unavailable if the client implementation is disabled.
+    #[cfg(feature = "client")]
+    pub fn current_session(&self) -> Option<SessionSnapshot> {
+        self.inner.with(|stack| {
+            let session = stack.top().scope.session.lock().unwrap();
+            session.as_ref().map(Session::snapshot)
+        })
+    }
+
+    /// Invokes a function that can modify the current Release Health Session, if any.
+    ///
+    /// See the global [`configure_session`](fn.configure_session.html)
+    /// for more documentation.
+    ///
+    /// This method is unavailable if the client implementation is disabled.
+    #[cfg(feature = "client")]
+    pub fn configure_session<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Session),
+    {
+        // the function is called outside of the lock of the stack, but holds
+        // the session lock, so that concurrent events still find the session
+        let slot = self.inner.with(|stack| stack.top().scope.session.clone());
+        let mut session = slot.lock().unwrap();
+        if let Some(ref mut session) = *session {
+            f(session);
+        }
+    }
+
+    /// Marks the current Release Health Session as `Abnormal`.
+    ///
+    /// See the global [`mark_session_abnormal`](fn.mark_session_abnormal.html)
+    /// for more documentation.
+    pub fn mark_session_abnormal(&self) {
+        with_client_impl! {{
+            self.inner.with(|stack| {
+                if let Some(session) = stack.top().scope.session.lock().unwrap().as_mut() {
+                    session.mark_abnormal();
+                }
+            })
+        }}
+    }
+
+    /// Sends a heartbeat for the current Release Health Session.
+    ///
+    /// See the global [`session_heartbeat`](fn.session_heartbeat.html)
+    /// for more documentation.
+    pub fn session_heartbeat(&self) -> bool {
+        with_client_impl! {{
+            // an expired session is rolled, and the heartbeat starts the new one
+            self.roll_expired_session();
+            let heartbeat = self.inner.with(|stack| {
+                let top = stack.top();
+                let mut session = top.scope.session.lock().unwrap();
+                let session = session.as_mut()?;
+                if !session.heartbeat() {
+                    return None;
+                }
+                Some((session.create_envelope_item(), top.client.clone()))
+            });
+            // the heartbeat is sent right away, as queued updates of the same
+            // session are coalesced into the latest one
+            match heartbeat {
+                Some((item, client)) => {
+                    if let (Some(item), Some(client)) = (item, client) {
                         let mut envelope = Envelope::new();
                         envelope.add_item(item);
-                        if let Some(ref client) = top.client {
-                            client.capture_envelope(envelope);
-                        }
+                        client.send_session_envelope(envelope);
                     }
+                    true
+                }
+                None => false,
+            }
+        }}
+    }
+
+    /// Captures a manually created Release Health Session update.
+    ///
+    /// See the global [`capture_session`](fn.capture_session.html)
+    /// for more documentation.
+    pub fn capture_session(&self, session_update: SessionUpdate<'static>) {
+        with_client_impl! {{
+            self.inner.with(|stack| {
+                if let Some(ref client) = stack.top().client {
+                    client.queue_session_update(session_update);
+                }
+            })
+        }}
+    }
+
+    /// Captures multiple manually created Release Health Session updates at once.
+    ///
+    /// See the global [`capture_sessions`](fn.capture_sessions.html)
+    /// for more documentation.
+    pub fn capture_sessions<I>(&self, session_updates: I)
+    where
+        I: IntoIterator<Item = SessionUpdate<'static>>,
+    {
+        with_client_impl! {{
+            self.inner.with(|stack| {
+                if let Some(ref client) = stack.top().client {
+                    client.queue_session_updates(session_updates.into_iter().collect());
                 }
             })
         }}
@@ -56,6 +56,7 @@ mod macros;
 mod api;
 mod breadcrumbs;
 mod clientoptions;
+mod clock;
 mod constants;
 mod error;
 mod futures;
@@ -63,18 +64,24 @@ mod hub;
 mod integration;
 mod intodsn;
 mod scope;
+mod session_tracking;
 mod transport;
 
 // public api or exports from this crate
 pub use crate::api::*;
 pub use crate::breadcrumbs::IntoBreadcrumbs;
-pub use crate::clientoptions::ClientOptions;
+pub use crate::clientoptions::{
+    ClientOptions, DistinctIdHasher, DistinctIdSource, SessionCallback, SessionDropPolicy,
+    SessionFlushScheduler, SessionMode,
+};
+pub use crate::clock::{Clock, SystemClock};
 pub use crate::error::{capture_error, event_from_error, parse_type_from_debug};
-pub use crate::futures::{SentryFuture, SentryFutureExt};
+pub use crate::futures::{SentryFuture, SentryFutureExt, SessionFuture};
 pub use crate::hub::Hub;
 pub use crate::integration::Integration;
 pub use crate::intodsn::IntoDsn;
 pub use crate::scope::{Scope, ScopeGuard};
+pub use crate::session_tracking::SessionTracking;
 pub use crate::transport::{Transport, TransportFactory};
 
 // client feature
@@ -83,7 +90,12 @@ mod client;
 #[cfg(feature = "client")]
 mod session;
 #[cfg(feature = "client")]
-pub use crate::client::Client;
+pub use crate::client::{CapturedEvent, Client};
+#[cfg(feature = "client")]
+pub use crate::session::{
+    Session, SessionCrashInfo, SessionFlushMetrics, SessionFlusherStats, SessionSnapshot,
+    SessionUpdateError,
+};
 
 // test utilities
 #[cfg(feature = "test")]
@@ -69,6 +69,26 @@ macro_rules! sentry_debug {
     }
 }
 
+/// Like `sentry_debug!`, but only prints when the given `debug` flag is set.
+///
+/// This does not access the current `Hub`, so it can be used while the hub is
+/// locked, and from background threads that do not have a bound client.
+#[allow(unused_macros)]
+macro_rules! sentry_debug_if {
+    ($debug:expr, $($arg:tt)*) => {
+        #[cfg(feature = "debug-logs")] {
+            let _ = &$debug;
+            ::log_::debug!(target: "sentry", $($arg)*);
+        }
+        #[cfg(not(feature = "debug-logs"))] {
+            if $debug {
+                eprint!("[sentry] ");
+                eprintln!($($arg)*);
+            }
+        }
+    }
+}
+
 #[allow(unused_macros)]
 macro_rules! minimal_unreachable {
     () => {
@@ -94,6 +94,12 @@ impl Scope {
         minimal_unreachable!();
     }
 
+    /// Sets whether errors captured with this scope count against the session.
+    pub fn set_ignore_for_session_health(&mut self, ignore: bool) {
+        let _ignore = ignore;
+        minimal_unreachable!();
+    }
+
     /// Add an event processor to the scope.
     pub fn add_event_processor(
         &mut self,
@@ -1,9 +1,11 @@
 use std::borrow::Cow;
 use std::fmt;
 use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Instant;
 
-use crate::protocol::{Breadcrumb, Context, Event, Level, User, Value};
+use crate::protocol::{Breadcrumb, Context, Event, Level, Transaction, User, Value};
 use crate::session::Session;
+use crate::types::{DateTime, Utc};
 use crate::Client;
 
 #[derive(Debug)]
@@ -43,6 +45,7 @@ pub struct Scope {
     pub(crate) contexts: im::HashMap<String, Context>,
     pub(crate) event_processors: im::Vector<Arc<EventProcessor>>,
     pub(crate) session: Arc<Mutex<Option<Session>>>,
+    pub(crate) ignore_for_session_health: bool,
 }
 
 impl fmt::Debug for Scope {
@@ -58,6 +61,7 @@ impl fmt::Debug for Scope {
             .field("contexts", &self.contexts)
             .field("event_processors", &self.event_processors.len())
             .field("session", &self.session)
+            .field("ignore_for_session_health", &self.ignore_for_session_health)
             .finish()
     }
 }
@@ -75,6 +79,7 @@ impl Default for Scope {
             contexts: Default::default(),
             event_processors: Default::default(),
             session: Default::default(),
+            ignore_for_session_health: false,
         }
     }
 }
@@ -83,12 +88,21 @@ impl Default for Scope {
 pub struct StackLayer {
     pub client: Option<Arc<Client>>,
     pub scope: Arc<Scope>,
+    // whether a session is started once a client with a release is bound
+    pub deferred_session: bool,
+    // when the application entered the background, per the session clock
+    pub backgrounded: Option<(Instant, DateTime<Utc>)>,
 }
 
 impl Stack {
     pub fn from_client_and_scope(client: Option<Arc<Client>>, scope: Arc<Scope>) -> Stack {
         Stack {
-            layers: vec![StackLayer { client, scope }],
+            layers: vec![StackLayer {
+                client,
+                scope,
+                deferred_session: false,
+                backgrounded: None,
+            }],
         }
     }
 
@@ -177,7 +191,12 @@ impl Scope {
     }
 
     /// Sets the user for the current scope.
+    ///
+    /// This also updates the distinct id of the currently active session.
     pub fn set_user(&mut self, user: Option<User>) {
+        if let Some(session) = self.session.lock().unwrap().as_mut() {
+            session.set_user(user.as_ref());
+        }
         self.user = user.map(Arc::new);
     }
 
@@ -212,6 +231,16 @@ impl Scope {
         self.extra.remove(key);
     }
 
+    /// Sets whether errors captured with this scope count against the session.
+    ///
+    /// Errors that are expected and handled, such as validation failures,
+    /// can be captured for visibility within a scope that ignores them for
+    /// session health, so that they do not mark the current session as
+    /// errored.  Crashes still crash the session.
+    pub fn set_ignore_for_session_health(&mut self, ignore: bool) {
+        self.ignore_for_session_health = ignore;
+    }
+
     /// Add an event processor to the scope.
     pub fn add_event_processor(
         &mut self,
@@ -269,9 +298,18 @@ impl Scope {
         Some(event)
     }
 
-    pub(crate) fn update_session_from_event(&self, event: &Event<'static>) {
+    /// Updates the session from the `event`, returning `true` if the event
+    /// errored or crashed the session.
+    pub(crate) fn update_session_from_event(&self, event: &Event<'static>) -> bool {
+        match self.session.lock().unwrap().as_mut() {
+            Some(session) => session.update_from_event(event, !self.ignore_for_session_health),
+            None => false,
+        }
+    }
+
+    pub(crate) fn update_session_from_transaction(&self, transaction: &Transaction<'static>) {
         if let Some(session) = self.session.lock().unwrap().as_mut() {
-            session.update_from_event(event);
+            session.update_from_transaction(transaction);
         }
     }
 }
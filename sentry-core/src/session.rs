@@ -2,113 +2,672 @@
 //!
 //! https://develop.sentry.dev/sdk/sessions/
 
-use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::fs;
+#[cfg(all(unix, feature = "session-signals"))]
+use std::io::Read;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::net::IpAddr;
+use std::os::raw::c_int;
+#[cfg(all(unix, feature = "session-signals"))]
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+#[cfg(all(unix, feature = "session-signals"))]
+use std::sync::Once;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock, TryLockError};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
+use rand::random;
+
 use crate::client::TransportArc;
+use crate::clientoptions::BeforeCallback;
 use crate::protocol::{
-    EnvelopeItem, Event, Level, SessionAttributes, SessionStatus, SessionUpdate,
+    Context, EnvelopeItem, Event, IpAddress, Level, SessionAttributes, SessionStatus,
+    SessionUpdate, Transaction, User,
 };
 use crate::scope::StackLayer;
-use crate::types::{Utc, Uuid};
-use crate::{Client, Envelope};
+use crate::types::{DateTime, TimeZone, Utc, Uuid};
+use crate::{
+    Client, ClientOptions, Clock, DistinctIdSource, Envelope, SessionDropPolicy, SessionMode,
+    SystemClock,
+};
+
+/// The release used for sessions when no release is configured, and
+/// `require_release_for_sessions` is disabled.
+const FALLBACK_RELEASE: &str = "unknown";
+
+/// Returns the distinct id to use for sessions of the given `user`.
+///
+/// This will be the first field of the user that is set, in the order of
+/// the `session_distinct_id_source` option.
+pub(crate) fn distinct_id_from_user(options: &ClientOptions, user: &User) -> Option<String> {
+    options
+        .session_distinct_id_source
+        .iter()
+        .filter_map(|source| match source {
+            DistinctIdSource::Id => user.id.as_ref(),
+            DistinctIdSource::Email => user.email.as_ref(),
+            DistinctIdSource::Username => user.username.as_ref(),
+        })
+        .next()
+        .cloned()
+}
+
+/// Returns the session attributes that are configured in the `options`.
+///
+/// These are the `release`, which falls back to `"unknown"` unless
+/// `require_release_for_sessions` is set, and the `environment`.  Returns
+/// `None` when sessions can not be tracked without a release.
+pub(crate) fn attributes_from_options(
+    options: &ClientOptions,
+) -> Option<SessionAttributes<'static>> {
+    let release = match options.release {
+        Some(ref release) => release.clone(),
+        None if !options.require_release_for_sessions => FALLBACK_RELEASE.into(),
+        None => return None,
+    };
+    let mut attributes = SessionAttributes::new(release);
+    attributes.environment = options.environment.clone();
+    Some(attributes)
+}
+
+/// Adds a monotonic `duration` to a wall clock `timestamp`.
+pub(crate) fn add_duration(timestamp: DateTime<Utc>, duration: Duration) -> Option<DateTime<Utc>> {
+    let mut secs = timestamp.timestamp() + duration.as_secs() as i64;
+    let mut nanos = timestamp.timestamp_subsec_nanos() + duration.subsec_nanos();
+    if nanos >= 1_000_000_000 {
+        secs += 1;
+        nanos -= 1_000_000_000;
+    }
+    Utc.timestamp_opt(secs, nanos).single()
+}
+
+/// Returns the `session_clock` of the `options`, or the system clock.
+pub(crate) fn session_clock(options: &ClientOptions) -> &dyn Clock {
+    match options.session_clock {
+        Some(ref clock) => clock.as_ref(),
+        None => &SystemClock,
+    }
+}
+
+/// Trims a `distinct_id`, and lowercases it if it looks like an email address.
+fn normalize_distinct_id(distinct_id: String) -> String {
+    let trimmed = distinct_id.trim();
+    if trimmed.contains('@') {
+        trimmed.to_lowercase()
+    } else if trimmed.len() != distinct_id.len() {
+        trimmed.to_owned()
+    } else {
+        distinct_id
+    }
+}
+
+/// Prepares a `distinct_id` to be sent, according to the `options`.
+///
+/// The id is normalized with `normalize_session_distinct_ids`, and then
+/// hashed with the `session_distinct_id_hasher`.
+fn prepare_distinct_id(options: &ClientOptions, distinct_id: Option<String>) -> Option<String> {
+    let distinct_id = if options.normalize_session_distinct_ids {
+        distinct_id.map(normalize_distinct_id)
+    } else {
+        distinct_id
+    };
+    match options.session_distinct_id_hasher {
+        Some(ref hasher) => distinct_id.map(|distinct_id| hasher(&distinct_id)),
+        None => distinct_id,
+    }
+}
+
+/// Whether a session could not be started because of a missing client.
+static SESSION_NO_CLIENT_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a session could not be started because of a missing release.
+static SESSION_NO_RELEASE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the missing release of a client with automatic session tracking was warned about.
+static SESSION_RELEASE_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Warns with the given `message`, but only once per `warned` flag.
+///
+/// The warning is always logged via the `log` crate, and is printed as well
+/// when the `debug` option is set.  Returns `true` if the warning was emitted.
+fn warn_once(warned: &AtomicBool, debug: bool, message: fmt::Arguments<'_>) -> bool {
+    if warned.swap(true, Ordering::Relaxed) {
+        return false;
+    }
+    log_::warn!(target: "sentry", "{}", message);
+    // with `debug-logs`, the message was already logged
+    if !cfg!(feature = "debug-logs") {
+        sentry_debug_if!(debug, "{}", message);
+    }
+    true
+}
+
+/// Warns about a session that could not be started, but only once per `warned` flag.
+///
+/// Calling `start_session` without a client or release is a common source of
+/// missing sessions.
+fn warn_session_not_started(warned: &AtomicBool, debug: bool, reason: &str) -> bool {
+    warn_once(
+        warned,
+        debug,
+        format_args!("no session was started: {}", reason),
+    )
+}
+
+/// Checks whether sessions can be started with the given client `options`.
+///
+/// Sessions are not tracked without a `release`, unless
+/// `require_release_for_sessions` is disabled.  When sessions are explicitly
+/// requested with `auto_session_tracking`, this is a misconfiguration, so it
+/// is warned about once, when the first such client is created.  Other
+/// clients only warn once a session is started.  Returns `false` for such a
+/// misconfiguration.
+pub(crate) fn check_session_options(options: &ClientOptions) -> bool {
+    let misconfigured = options.auto_session_tracking
+        && options.session_mode != SessionMode::Disabled
+        && options.release.is_none()
+        && options.require_release_for_sessions;
+    if misconfigured {
+        warn_once(
+            &SESSION_RELEASE_WARNED,
+            options.debug,
+            format_args!(
+                "automatic session tracking is enabled, but sessions will not be tracked \
+                 without a release; set a `release` or disable `auto_session_tracking`"
+            ),
+        );
+    }
+    !misconfigured
+}
+
+/// Returns the user agent to use for sessions, based on the `os` context of the scope.
+///
+/// This will be the OS name, followed by its version if known, like `Android/11`.
+fn user_agent_from_contexts(contexts: &im::HashMap<String, Context>) -> Option<String> {
+    let os = match contexts.get("os") {
+        Some(Context::Os(os)) => os,
+        _ => return None,
+    };
+    let name = os.name.as_ref()?;
+    Some(match os.version {
+        Some(ref version) => format!("{}/{}", name, version),
+        None => name.clone(),
+    })
+}
+
+/// A read-only snapshot of a Release Health Session.
+///
+/// See [`current_session`](fn.current_session.html).
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct SessionSnapshot {
+    /// The session identifier.
+    pub session_id: Uuid,
+    /// The current status of the session.
+    pub status: SessionStatus,
+    /// The number of errors that occurred in the session so far.
+    pub errors: u64,
+    /// The timestamp of when the session started.
+    pub started: DateTime<Utc>,
+    /// Information about the event that crashed the session, if it crashed.
+    pub crash_info: Option<SessionCrashInfo>,
+}
+
+/// Information about the event that crashed a Release Health Session.
+///
+/// The session protocol has no way to refer to the crashing event, so this
+/// is only available locally, for example via
+/// [`current_session`](fn.current_session.html).
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct SessionCrashInfo {
+    /// The id of the event that crashed the session.
+    ///
+    /// The event itself might not have been sent, for example when it was
+    /// sampled out or dropped by `before_send`.
+    pub event_id: Uuid,
+    /// The type of the first unhandled exception of the event.
+    ///
+    /// For `Fatal` events that crashed the session because of the
+    /// `session_fatal_is_crash` option, this is the type of the first
+    /// exception, or empty if the event has none.
+    pub exception_type: String,
+}
 
-#[derive(Clone, Debug)]
+/// A Release Health Session.
+///
+/// The current session can be configured with
+/// [`configure_session`](fn.configure_session.html).
+///
+/// Sessions are intentionally not `Clone`.  A session is shared between a
+/// scope and all the hubs forked from it, so that only a single session
+/// update with the `init` flag is ever sent per session.
+///
+/// # Concurrency
+///
+/// A session is `Send` and `Sync`, and lives behind a `Mutex` in its scope,
+/// which serializes all updates from the hubs that share it.  The hub never
+/// holds its stack lock while a session is closed or dropped, as sending the
+/// final update might need to access the hub again.  The final update is
+/// queued exactly once, when the session is dropped or taken from the scope.
+#[derive(Debug)]
 pub struct Session {
     client: Arc<Client>,
     session_update: SessionUpdate<'static>,
     started: Instant,
     dirty: bool,
+    crash_info: Option<SessionCrashInfo>,
 }
 
 impl Drop for Session {
     fn drop(&mut self) {
         self.close();
         if self.dirty {
-            self.client.enqueue_session(self.session_update.clone());
+            advance_sequence(&mut self.session_update);
+            self.client
+                .queue_session_update(self.session_update.clone());
         }
+        self.client.untrack_session(&self.session_update.session_id);
     }
 }
 
 impl Session {
-    pub fn from_stack(stack: &StackLayer) -> Option<Self> {
-        let client = stack.client.as_ref()?;
+    /// Whether a session can not be started only because no client with a
+    /// `release` is bound yet.
+    pub(crate) fn awaits_release(stack: &StackLayer) -> bool {
+        match stack.client {
+            Some(ref client) => {
+                let options = client.options();
+                options.session_mode != SessionMode::Disabled
+                    && options.release.is_none()
+                    && options.require_release_for_sessions
+            }
+            None => true,
+        }
+    }
+
+    pub(crate) fn from_stack(stack: &StackLayer) -> Option<Self> {
+        let client = match stack.client {
+            Some(ref client) => client,
+            None => {
+                warn_session_not_started(&SESSION_NO_CLIENT_WARNED, false, "no client is bound");
+                return None;
+            }
+        };
         let options = client.options();
+        if options.session_mode == SessionMode::Disabled {
+            return None;
+        }
+        let mut attributes = match attributes_from_options(options) {
+            Some(attributes) => attributes,
+            None => {
+                warn_session_not_started(
+                    &SESSION_NO_RELEASE_WARNED,
+                    options.debug,
+                    "no release is configured, see `require_release_for_sessions`",
+                );
+                return None;
+            }
+        };
+        if !client.sample_session() {
+            sentry_debug_if!(options.debug, "session was sampled out");
+            return None;
+        }
         let user = stack.scope.user.as_ref();
-        let distinct_id = user
-            .and_then(|user| {
-                user.id
-                    .as_ref()
-                    .or_else(|| user.email.as_ref())
-                    .or_else(|| user.username.as_ref())
-            })
-            .cloned();
-        Some(Self {
+        let distinct_id = prepare_distinct_id(
+            options,
+            user.and_then(|user| distinct_id_from_user(options, user)),
+        );
+        // the ip address is only attached when sending PII is allowed
+        let ip_address = match user.and_then(|user| user.ip_address) {
+            Some(IpAddress::Exact(addr)) if options.send_default_pii => Some(addr),
+            _ => None,
+        };
+        // the OS is reported via the user agent, which is considered PII as well
+        let user_agent = if options.send_default_pii {
+            user_agent_from_contexts(&stack.scope.contexts)
+        } else {
+            None
+        };
+        // the tags are sorted by key, so that the same tags are kept when
+        // there are more than `max_session_tags`
+        let tags: BTreeMap<_, _> = stack.scope.tags.iter().collect();
+        attributes.ip_address = ip_address;
+        attributes.user_agent = user_agent;
+        attributes.tags = tags
+            .into_iter()
+            .take(options.max_session_tags)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let session = Self {
             client: client.clone(),
             session_update: SessionUpdate {
                 session_id: Uuid::new_v4(),
                 distinct_id,
                 sequence: None,
                 timestamp: None,
-                started: Utc::now(),
+                started: session_clock(options).now_utc(),
                 init: true,
                 duration: None,
                 status: SessionStatus::Ok,
                 errors: 0,
-                attributes: SessionAttributes {
-                    release: options.release.clone()?,
-                    environment: options.environment.clone(),
-                    ip_address: None,
-                    user_agent: None,
-                },
+                attributes,
             },
-            started: Instant::now(),
+            started: session_clock(options).now_instant(),
             dirty: true,
-        })
+            crash_info: None,
+        };
+        client.track_session(&session.session_update, session.started);
+        sentry_debug_if!(
+            options.debug,
+            "started session {}",
+            session.session_update.session_id
+        );
+        Some(session)
     }
 
-    pub(crate) fn update_from_event(&mut self, event: &Event<'static>) {
-        if self.session_update.status != SessionStatus::Ok {
+    /// Updates the session from a captured `event`.
+    ///
+    /// Events with an exception, or with a level of at least the
+    /// `session_error_level_threshold`, count as errors.  The `errors` count
+    /// is incremented at most once per event, no matter how many exceptions
+    /// it carries, and saturates at `max_session_errors`.  Errors are not
+    /// counted at all without `count_errors`, when the event was captured
+    /// with a scope that ignores it for session health.
+    /// The session is marked as `Crashed` when any of the exceptions is
+    /// explicitly unhandled, or for any `Fatal` event with the
+    /// `session_fatal_is_crash` option, and remembers that event as its
+    /// `crash_info`.  The `on_session_crash` callback is invoked
+    /// once the session has crashed.
+    ///
+    /// Returns `true` if the event counted as an error of the session, or
+    /// crashed it.
+    pub(crate) fn update_from_event(&mut self, event: &Event<'static>, count_errors: bool) -> bool {
+        if self.session_update.is_terminal() {
             // a session that has already transitioned to a "terminal" state
             // should not receive any more updates
+            return false;
+        }
+        let threshold = self.client.options().session_error_level_threshold;
+        let has_error = event.level >= threshold || !event.exception.values.is_empty();
+        let unhandled = event.exception.values.iter().find(|exc| {
+            exc.mechanism
+                .as_ref()
+                .and_then(|mechanism| mechanism.handled)
+                == Some(false)
+        });
+        let crash = match unhandled {
+            Some(exc) => Some(exc.ty.clone()),
+            None if event.level == Level::Fatal && self.client.options().session_fatal_is_crash => {
+                let exc = event.exception.values.first();
+                Some(exc.map(|exc| exc.ty.clone()).unwrap_or_default())
+            }
+            None => None,
+        };
+
+        let affected = crash.is_some() || (has_error && count_errors);
+        if let Some(exception_type) = crash {
+            self.session_update.status = SessionStatus::Crashed;
+            self.crash_info = Some(SessionCrashInfo {
+                event_id: event.event_id,
+                exception_type,
+            });
+            self.dirty = true;
+            sentry_debug_if!(self.debug(), "session {} crashed", self.session_id());
+        }
+        if has_error && count_errors {
+            self.record_error();
+        }
+        // the session was still ongoing, so it crashed because of this event
+        if self.session_update.status == SessionStatus::Crashed {
+            if let Some(ref on_session_crash) = self.client.options().on_session_crash {
+                on_session_crash(&self.session_update);
+            }
+        }
+        affected
+    }
+
+    fn record_error(&mut self) {
+        // the session is marked as errored by its first error, the count
+        // itself is not meaningful beyond the configured cap
+        let max_errors = self.client.options().max_session_errors.max(1);
+        if self.session_update.errors >= max_errors {
             return;
         }
-        let mut has_error = event.level >= Level::Error;
-        let mut is_crash = false;
-        for exc in &event.exception.values {
-            has_error = true;
-            if let Some(mechanism) = &exc.mechanism {
-                if let Some(false) = mechanism.handled {
-                    is_crash = true;
-                    break;
-                }
+        self.session_update.errors += 1;
+        self.dirty = true;
+        sentry_debug_if!(
+            self.debug(),
+            "session {} recorded an error, {} errors in total",
+            self.session_id(),
+            self.session_update.errors
+        );
+    }
+
+    fn debug(&self) -> bool {
+        self.client.options().debug
+    }
+
+    pub(crate) fn session_id(&self) -> Uuid {
+        self.session_update.session_id
+    }
+
+    /// Drops a session that was never put on a scope, without sending any
+    /// update for it.
+    pub(crate) fn discard(mut self) {
+        // a terminal session is not closed again when dropped
+        self.session_update.status = SessionStatus::Exited;
+        self.dirty = false;
+    }
+
+    /// Updates the `duration` and `timestamp` of the session.
+    ///
+    /// The duration is always derived from the monotonic clock, and the
+    /// `timestamp` is derived from the `started` timestamp and that duration,
+    /// so that both remain consistent even when the wall clock jumps.
+    fn update_duration(&mut self) {
+        let duration = self.update_timestamp();
+        self.session_update.duration = Some(sanitize_duration(duration.as_secs_f64()));
+    }
+
+    /// Returns the monotonic time elapsed since the session started.
+    pub(crate) fn elapsed(&self) -> Duration {
+        session_clock(self.client.options())
+            .now_instant()
+            .saturating_duration_since(self.started)
+    }
+
+    /// Updates the `timestamp` of the session to the current time.
+    ///
+    /// Returns the monotonic time elapsed since the session started.
+    fn update_timestamp(&mut self) -> Duration {
+        let duration = self.elapsed();
+        self.session_update.timestamp = add_duration(self.session_update.started, duration);
+        duration
+    }
+
+    /// Updates the session from a captured `transaction`.
+    ///
+    /// A transaction counts as one error when the status of its trace context
+    /// is anything other than `ok` or `cancelled`.
+    pub(crate) fn update_from_transaction(&mut self, transaction: &Transaction<'static>) {
+        if self.session_update.is_terminal() {
+            // a session that has already transitioned to a "terminal" state
+            // should not receive any more updates
+            return;
+        }
+        let status = match transaction.contexts.get("trace") {
+            Some(Context::Trace(trace)) => trace.status.as_deref(),
+            _ => None,
+        };
+        if let Some(status) = status {
+            if status != "ok" && status != "cancelled" {
+                self.record_error();
             }
         }
+    }
 
-        if is_crash {
-            self.session_update.status = SessionStatus::Crashed;
+    pub(crate) fn close(&mut self) {
+        self.close_with_status(SessionStatus::Exited);
+    }
+
+    /// Closes the session with the given terminal `status`.
+    ///
+    /// An `Ok` status is treated as `Exited`.  Sessions that already are in a
+    /// terminal state are not changed, in which case `false` is returned.
+    pub(crate) fn close_with_status(&mut self, status: SessionStatus) -> bool {
+        if self.session_update.is_terminal() {
+            return false;
+        }
+        self.update_duration();
+        self.session_update.status = match status {
+            SessionStatus::Ok => SessionStatus::Exited,
+            status => status,
+        };
+        self.dirty = true;
+        sentry_debug_if!(
+            self.debug(),
+            "session {} ended as {} after {:.3}s",
+            self.session_id(),
+            self.session_update.status,
+            self.session_update.duration.unwrap_or_default()
+        );
+        true
+    }
+
+    /// Closes the session as `Exited` at the given end `timestamp`.
+    ///
+    /// The duration is the time between the start of the session and the
+    /// `timestamp`, or zero when the `timestamp` is before the start.  Sessions
+    /// that already are in a terminal state are not changed, in which case
+    /// `false` is returned.
+    pub(crate) fn close_at(&mut self, timestamp: DateTime<Utc>) -> bool {
+        if self.session_update.is_terminal() {
+            return false;
         }
-        if has_error {
-            self.session_update.errors += 1;
+        let started = self.session_update.started;
+        let duration = timestamp
+            .signed_duration_since(started)
+            .to_std()
+            .unwrap_or_default();
+        self.session_update.timestamp = Some(timestamp.max(started));
+        self.session_update.duration = Some(sanitize_duration(duration.as_secs_f64()));
+        self.session_update.status = SessionStatus::Exited;
+        self.dirty = true;
+        sentry_debug_if!(
+            self.debug(),
+            "session {} ended at {} after {:.3}s",
+            self.session_id(),
+            timestamp,
+            duration.as_secs_f64()
+        );
+        true
+    }
+
+    /// Updates the distinct id of the session from the `user`, for example
+    /// when a user logs in.
+    ///
+    /// The session is only marked as dirty when the distinct id actually changed.
+    pub(crate) fn set_user(&mut self, user: Option<&User>) {
+        let options = self.client.options();
+        let distinct_id = user.and_then(|user| distinct_id_from_user(options, user));
+        let distinct_id = prepare_distinct_id(options, distinct_id);
+        if self.session_update.distinct_id != distinct_id {
+            self.session_update.distinct_id = distinct_id;
             self.dirty = true;
         }
     }
 
-    pub(crate) fn close(&mut self) {
-        if self.session_update.status == SessionStatus::Ok {
-            self.session_update.duration = Some(self.started.elapsed().as_secs_f64());
-            self.session_update.status = SessionStatus::Exited;
+    /// Sets the ip address of the client that is using the session.
+    ///
+    /// The ip address is only attached when `send_default_pii` is enabled, and
+    /// ignored otherwise.  It is used for filtering and not persisted.
+    pub fn set_ip_address(&mut self, ip_address: Option<IpAddr>) {
+        if !self.client.options().send_default_pii {
+            return;
+        }
+        if self.session_update.attributes.ip_address != ip_address {
+            self.session_update.attributes.ip_address = ip_address;
+            self.dirty = true;
+        }
+    }
+
+    /// Sets the user agent of the client that is using the session.
+    ///
+    /// This takes precedence over the user agent derived from the `os` context.
+    pub fn set_user_agent(&mut self, user_agent: Option<String>) {
+        if self.session_update.attributes.user_agent != user_agent {
+            self.session_update.attributes.user_agent = user_agent;
+            self.dirty = true;
+        }
+    }
+
+    /// Marks the session as `Abnormal`, unless it already is in a terminal state.
+    pub(crate) fn mark_abnormal(&mut self) {
+        if !self.session_update.is_terminal() {
+            self.update_duration();
+            self.session_update.status = SessionStatus::Abnormal;
             self.dirty = true;
+            sentry_debug_if!(
+                self.debug(),
+                "session {} marked abnormal after {:.3}s",
+                self.session_id(),
+                self.session_update.duration.unwrap_or_default()
+            );
+        }
+    }
+
+    /// Creates a read-only snapshot of the current state of the session.
+    pub(crate) fn snapshot(&self) -> SessionSnapshot {
+        SessionSnapshot {
+            session_id: self.session_update.session_id,
+            status: self.session_update.status,
+            errors: self.session_update.errors,
+            started: self.session_update.started,
+            crash_info: self.crash_info().cloned(),
+        }
+    }
+
+    /// Returns information about the event that crashed the session, if any.
+    pub fn crash_info(&self) -> Option<&SessionCrashInfo> {
+        self.crash_info.as_ref()
+    }
+
+    /// Marks the session as dirty, so that its next envelope item signals
+    /// that the session is still alive.
+    ///
+    /// Returns `false` for sessions that already ended, which do not send
+    /// heartbeats.
+    pub(crate) fn heartbeat(&mut self) -> bool {
+        if self.session_update.is_terminal() {
+            return false;
         }
+        self.dirty = true;
+        true
     }
 
     pub(crate) fn create_envelope_item(&mut self) -> Option<EnvelopeItem> {
         if self.dirty {
-            let item = self.session_update.clone().into();
+            if !self.session_update.is_terminal() {
+                self.update_timestamp();
+            }
+            advance_sequence(&mut self.session_update);
+            // the item is sent right away, instead of being queued
+            let session_update = self.client.before_send_session(self.session_update.clone());
             self.session_update.init = false;
             self.dirty = false;
-            return Some(item);
+            self.client
+                .track_session(&self.session_update, self.started);
+            return session_update.map(Into::into);
         }
         None
     }
@@ -116,193 +675,4763 @@ impl Session {
 
 // as defined here: https://develop.sentry.dev/sdk/envelopes/#size-limits
 const MAX_SESSION_ITEMS: usize = 100;
-const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+// the approximate serialized size of the fixed-size fields of a session update,
+// like its `sid`, `status`, timestamps and the JSON syntax around them.
+const SESSION_UPDATE_BASE_SIZE: usize = 250;
+const MIN_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+// the signals for which the session signal handler is installed.
+#[cfg(all(unix, feature = "session-signals"))]
+const FLUSH_SIGNALS: [c_int; 2] = [signal_hook::SIGTERM, signal_hook::SIGINT];
+// the upper bound of the exponent of the retry backoff, to avoid overflows.
+const MAX_RETRY_BACKOFF_EXPONENT: usize = 16;
+// the number of flushes for which metrics are kept.
+const RECENT_FLUSH_METRICS: usize = 16;
 
-type SessionQueue = Arc<Mutex<Vec<SessionUpdate<'static>>>>;
+/// Counters shared between the flusher and its background thread.
+#[derive(Default)]
+struct FlusherStats {
+    sent: AtomicUsize,
+    dropped_no_transport: AtomicUsize,
+    dropped_backpressure: AtomicUsize,
+}
 
-/// Background Session Flusher
+/// Statistics about the session updates processed by a `Client`.
 ///
-/// The background flusher queues session updates for delayed batched sending.
-/// It has its own background thread that will flush its queue once every
-/// `FLUSH_INTERVAL`.
+/// See [`Client::session_stats`](struct.Client.html#method.session_stats).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct SessionFlusherStats {
+    /// The number of session updates that were sent to the transport.
+    pub sent: usize,
+    /// The number of session updates that were dropped because no transport was available.
+    pub dropped_no_transport: usize,
+    /// The number of session updates that were dropped because the transport
+    /// repeatedly rejected them, or because the queue overflowed.
+    pub dropped_backpressure: usize,
+}
+
+/// Metrics about a single flush of the session updates queued by a `Client`.
 ///
-/// For now it just batches all the session updates together into one envelope,
-/// but in the future it will also pre-aggregate session numbers.
-pub(crate) struct SessionFlusher {
+/// See [`Client::recent_session_flush_metrics`](struct.Client.html#method.recent_session_flush_metrics).
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct SessionFlushMetrics {
+    /// How long it took to hand the session updates to the transport.
+    pub duration: Duration,
+    /// The number of session updates that were flushed.
+    pub items: usize,
+    /// The estimated size of the flushed session updates in bytes.
+    pub bytes: usize,
+}
+
+/// Locks a `mutex` of the flusher, even if another thread panicked while holding it.
+///
+/// Session updates are only ever moved in and out of the queue as a whole,
+/// so the state of the flusher remains consistent after a panic.
+fn locked<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks a `mutex` of the flusher without blocking.
+///
+/// Returns `None` if the mutex is held, which is forever in a forked child
+/// process when another thread of the parent held it at the time of the fork.
+fn try_locked<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    match mutex.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(err)) => Some(err.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// The queued session updates, together with their total estimated size.
+#[derive(Default)]
+struct SessionQueue {
+    updates: Vec<SessionUpdate<'static>>,
+    bytes: usize,
+}
+
+impl SessionQueue {
+    fn from_updates(updates: Vec<SessionUpdate<'static>>) -> Self {
+        let bytes = updates.iter().map(estimated_size).sum();
+        SessionQueue { updates, bytes }
+    }
+
+    fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    fn push(&mut self, session_update: SessionUpdate<'static>) {
+        self.bytes += estimated_size(&session_update);
+        self.updates.push(session_update);
+    }
+
+    /// Takes all the updates, leaving the queue empty.
+    fn take(&mut self) -> Vec<SessionUpdate<'static>> {
+        self.bytes = 0;
+        mem::take(&mut self.updates)
+    }
+
+    fn clear(&mut self) {
+        self.take();
+    }
+
+    /// Removes the `count` oldest updates.
+    fn drop_oldest(&mut self, count: usize) {
+        for session_update in self.updates.drain(..count) {
+            self.bytes -= estimated_size(&session_update);
+        }
+    }
+
+    /// Removes the newest updates beyond the first `len` ones.
+    fn truncate(&mut self, len: usize) {
+        for session_update in self.updates.drain(len..) {
+            self.bytes -= estimated_size(&session_update);
+        }
+    }
+}
+
+/// The state shared between the flusher and its background thread.
+struct FlusherState {
     transport: TransportArc,
-    queue: SessionQueue,
-    shutdown: Arc<(Mutex<bool>, Condvar)>,
-    worker: Option<JoinHandle<()>>,
+    queue: Mutex<SessionQueue>,
+    before_send: Option<BeforeCallback<SessionUpdate<'static>>>,
+    stats: FlusherStats,
+    max_items: usize,
+    max_bytes: usize,
+    // the hard limit of the queue, and which updates are dropped beyond it
+    max_queued: Option<usize>,
+    drop_policy: SessionDropPolicy,
+    flush_interval: Duration,
+    flush_jitter: f32,
+    flush_debounce: Option<Duration>,
+    // when the first update was queued into an empty queue, with a `flush_debounce`
+    queued_at: Mutex<Option<Instant>>,
+    debug: bool,
+    disabled: bool,
+    dry_run: bool,
+    // cleared in a forked child, which must not send the updates of its parent
+    flush_on_drop: AtomicBool,
+    thread_name: String,
+    stack_size: Option<usize>,
+    max_retries: usize,
+    // the transport of the events, which is shut down together with the
+    // session `transport` before a signal is re-raised
+    event_transport: Option<TransportArc>,
+    shutdown_timeout: Duration,
+    spawn_thread: SpawnThread,
+    signals: SignalHandler,
+    failed_flushes: AtomicUsize,
+    spool_dir: Option<PathBuf>,
+    clock: Arc<dyn Clock>,
+    recent_flushes: Mutex<VecDeque<SessionFlushMetrics>>,
+    open_sessions: Mutex<HashMap<Uuid, (SessionUpdate<'static>, Instant)>>,
 }
 
-impl SessionFlusher {
-    /// Creates a new Flusher that will submit envelopes to the given `transport`.
-    pub fn new(transport: TransportArc) -> Self {
-        let queue = Arc::new(Mutex::new(Vec::new()));
-        #[allow(clippy::mutex_atomic)]
-        let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
+impl FlusherState {
+    /// Returns a copy of the state with an empty queue and fresh locks.
+    ///
+    /// This replaces the state in a forked child process, when another
+    /// thread of the parent held one of its locks at the time of the fork.
+    fn forked(&self) -> Self {
+        FlusherState {
+            transport: self.transport.clone(),
+            queue: Mutex::new(SessionQueue::default()),
+            before_send: self.before_send.clone(),
+            stats: FlusherStats::default(),
+            max_items: self.max_items,
+            max_bytes: self.max_bytes,
+            max_queued: self.max_queued,
+            drop_policy: self.drop_policy,
+            flush_interval: self.flush_interval,
+            flush_jitter: self.flush_jitter,
+            flush_debounce: self.flush_debounce,
+            queued_at: Mutex::new(None),
+            debug: self.debug,
+            disabled: self.disabled,
+            dry_run: self.dry_run,
+            flush_on_drop: AtomicBool::new(self.flush_on_drop.load(Ordering::Relaxed)),
+            thread_name: self.thread_name.clone(),
+            stack_size: self.stack_size,
+            max_retries: self.max_retries,
+            event_transport: self.event_transport.clone(),
+            shutdown_timeout: self.shutdown_timeout,
+            spawn_thread: self.spawn_thread,
+            signals: self.signals.clone(),
+            failed_flushes: AtomicUsize::new(0),
+            spool_dir: self.spool_dir.clone(),
+            clock: self.clock.clone(),
+            recent_flushes: Mutex::new(VecDeque::with_capacity(RECENT_FLUSH_METRICS)),
+            open_sessions: Mutex::new(HashMap::new()),
+        }
+    }
 
-        let worker_transport = transport.clone();
-        let worker_queue = queue.clone();
-        let worker_shutdown = shutdown.clone();
-        let worker = std::thread::Builder::new()
-            .name("sentry-session-flusher".into())
-            .spawn(move || {
-                let (lock, cvar) = worker_shutdown.as_ref();
-                let mut shutdown = lock.lock().unwrap();
-                // check this immediately, in case the main thread is already shutting down
-                if *shutdown {
-                    return;
+    /// Flushes the queue if a signal was recorded by the signal handler.
+    ///
+    /// The transports are then shut down within the `shutdown_timeout`, so
+    /// that they deliver the flushed envelopes before the process terminates.
+    /// Returns the recorded signal, which should then be re-raised.
+    fn handle_pending_signal(&self) -> Option<c_int> {
+        let signal = self.signals.take_pending()?;
+        sentry_debug_if!(self.debug, "flushing sessions after signal {}", signal);
+        self.flush();
+
+        let started = Instant::now();
+        let transports = Some(&self.transport)
+            .into_iter()
+            .chain(&self.event_transport);
+        for transport in transports {
+            // both are the same when there is no dedicated session transport
+            let transport = match transport.write().unwrap().take() {
+                Some(transport) => transport,
+                None => continue,
+            };
+            let remaining = self
+                .shutdown_timeout
+                .checked_sub(started.elapsed())
+                .unwrap_or_default();
+            if !transport.shutdown(remaining) {
+                sentry_debug_if!(self.debug, "transport did not shut down in time");
+            }
+        }
+        Some(signal)
+    }
+
+    /// Returns how long until the queued updates should be flushed because of
+    /// the `flush_debounce`, if any updates were queued with a debounce.
+    fn debounce_remaining(&self) -> Option<Duration> {
+        let debounce = self.flush_debounce?;
+        let queued_at = (*locked(&self.queued_at))?;
+        Some(
+            debounce
+                .checked_sub(queued_at.elapsed())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Flushes the queue to the transport.
+    ///
+    /// This will be called from both the background thread and the main
+    /// thread on drop.  Updates that the transport rejects are put back into
+    /// the queue, to be retried on the next flush.
+    fn flush(&self) -> bool {
+        let (sent, rejected) = match self.send_queue() {
+            Some(flushed) => flushed,
+            None => return false,
+        };
+        if rejected.is_empty() {
+            self.failed_flushes.store(0, Ordering::Relaxed);
+        } else {
+            self.requeue(rejected);
+        }
+        sent
+    }
+
+    /// Takes all the queued updates at once and sends them to the transport.
+    ///
+    /// Returns whether any update was sent, and the updates the transport
+    /// rejected, or `None` if the queue was empty.
+    fn send_queue(&self) -> Option<(bool, Vec<SessionUpdate<'static>>)> {
+        let queue: Vec<_> = {
+            let mut queue = locked(&self.queue);
+            *locked(&self.queued_at) = None;
+            queue.take()
+        };
+
+        if queue.is_empty() {
+            return None;
+        }
+
+        let start = Instant::now();
+        let mut envelope = Envelope::new();
+        let mut items = 0;
+        let mut bytes = 0;
+        let mut total_bytes = 0;
+        let mut sent = false;
+        let mut rejected = vec![];
+
+        let queue = coalesce_updates(queue);
+        let total_items = queue.len();
+        sentry_debug_if!(self.debug, "flushing {} session updates", total_items);
+        for mut session_update in queue {
+            // updates of ongoing sessions are sent with the time they are
+            // flushed, the timestamp of closed sessions matches their duration
+            if !session_update.is_terminal() {
+                session_update.timestamp = Some(self.clock.now_utc());
+            }
+            let session_update = match self.before_send(session_update) {
+                Some(session_update) => session_update,
+                None => continue,
+            };
+            let size = estimated_size(&session_update);
+            if items >= self.max_items || (items > 0 && bytes + size > self.max_bytes) {
+                sent |= self.send_envelope(envelope, items, &mut rejected);
+                envelope = Envelope::new();
+                items = 0;
+                bytes = 0;
+            }
+            envelope.add_item(session_update);
+            items += 1;
+            bytes += size;
+            total_bytes += size;
+        }
+
+        // `before_send_session` might have dropped all the updates
+        if items > 0 {
+            sent |= self.send_envelope(envelope, items, &mut rejected);
+        }
+        self.record_flush(SessionFlushMetrics {
+            duration: start.elapsed(),
+            items: total_items,
+            bytes: total_bytes,
+        });
+        Some((sent, rejected))
+    }
+
+    /// Passes a session update that is about to be sent through the
+    /// `before_send_session` callback.
+    ///
+    /// Returns `None` if the callback dropped the update.
+    fn before_send(
+        &self,
+        session_update: SessionUpdate<'static>,
+    ) -> Option<SessionUpdate<'static>> {
+        let func = match self.before_send {
+            Some(ref func) => func,
+            None => return Some(session_update),
+        };
+        let id = session_update.session_id;
+        let mut session_update = match func(session_update) {
+            Some(session_update) => session_update,
+            None => {
+                sentry_debug_if!(self.debug, "before_send_session dropped session {}", id);
+                return None;
+            }
+        };
+        // the callback might have set an invalid duration
+        session_update.duration = session_update.duration.map(sanitize_duration);
+        Some(session_update)
+    }
+
+    /// Flushes the queue from the background thread, catching any panic.
+    ///
+    /// A panicking transport would otherwise kill the background thread,
+    /// and queued session updates would no longer be flushed periodically.
+    /// The updates of the panicked flush are lost.
+    fn flush_catching(&self) -> bool {
+        match panic::catch_unwind(AssertUnwindSafe(|| self.flush())) {
+            Ok(sent) => sent,
+            Err(_) => {
+                sentry_debug_if!(self.debug, "flushing sessions panicked");
+                false
+            }
+        }
+    }
+
+    /// Records the `metrics` of a flush, keeping only the most recent ones.
+    fn record_flush(&self, metrics: SessionFlushMetrics) {
+        let mut recent_flushes = locked(&self.recent_flushes);
+        if recent_flushes.len() >= RECENT_FLUSH_METRICS {
+            recent_flushes.pop_front();
+        }
+        recent_flushes.push_back(metrics);
+    }
+
+    /// Sends an envelope with `items` session updates, and records them in the `stats`.
+    ///
+    /// When the transport rejects the envelope, its session updates are moved
+    /// to `rejected`.  In a dry run, the envelope is discarded instead.
+    fn send_envelope(
+        &self,
+        envelope: Envelope,
+        items: usize,
+        rejected: &mut Vec<SessionUpdate<'static>>,
+    ) -> bool {
+        if self.dry_run {
+            sentry_debug_if!(
+                self.debug,
+                "session dry run; not sending envelope with {} session updates",
+                items
+            );
+            return false;
+        }
+        if let Some(ref transport) = *self.transport.read().unwrap() {
+            match transport.try_send_envelope(envelope) {
+                Ok(()) => {
+                    self.stats.sent.fetch_add(items, Ordering::Relaxed);
+                    true
                 }
-                let mut last_flush = Instant::now();
-                loop {
-                    let timeout = FLUSH_INTERVAL - last_flush.elapsed();
-                    shutdown = cvar.wait_timeout(shutdown, timeout).unwrap().0;
-                    if *shutdown {
-                        return;
-                    }
-                    if last_flush.elapsed() < FLUSH_INTERVAL {
-                        continue;
-                    }
-                    SessionFlusher::flush(worker_queue.lock().unwrap(), &worker_transport);
-                    last_flush = Instant::now();
+                Err(envelope) => {
+                    rejected.extend(envelope.items().filter_map(|item| match item {
+                        EnvelopeItem::SessionUpdate(session_update) => Some(session_update.clone()),
+                        _ => None,
+                    }));
+                    false
                 }
+            }
+        } else {
+            self.stats
+                .dropped_no_transport
+                .fetch_add(items, Ordering::Relaxed);
+            false
+        }
+    }
+
+    /// Queues an `Abnormal` update for all sessions that were never closed.
+    ///
+    /// The updates are based on the last state of the session that was sent.
+    fn abandon_open_sessions(&self) {
+        let open_sessions = std::mem::take(&mut *locked(&self.open_sessions));
+        if open_sessions.is_empty() {
+            return;
+        }
+        sentry_debug_if!(
+            self.debug,
+            "reporting {} sessions that were never closed as abnormal",
+            open_sessions.len()
+        );
+        let mut queue = locked(&self.queue);
+        for (_, (mut session_update, started)) in open_sessions {
+            if !session_update.is_terminal() {
+                let duration = self.clock.now_instant().saturating_duration_since(started);
+                session_update.duration = Some(sanitize_duration(duration.as_secs_f64()));
+                session_update.status = SessionStatus::Abnormal;
+                advance_sequence(&mut session_update);
+            }
+            queue.push(session_update);
+        }
+    }
+
+    /// Puts session updates that were rejected by the transport back into the queue.
+    ///
+    /// The rejected updates are older than anything that was queued in the
+    /// meantime, and the oldest updates are dropped first when the queue would
+    /// grow beyond `max_items`.  After `max_retries` consecutive retries
+    /// were rejected as well, the rejected updates are dropped entirely.
+    fn requeue(&self, mut rejected: Vec<SessionUpdate<'static>>) {
+        let attempts = self.failed_flushes.fetch_add(1, Ordering::Relaxed) + 1;
+        if attempts > self.max_retries {
+            self.failed_flushes.store(0, Ordering::Relaxed);
+            if self.spool(&rejected) {
+                return;
+            }
+            sentry_debug_if!(
+                self.debug,
+                "dropping {} session updates rejected by the transport",
+                rejected.len()
+            );
+            self.stats
+                .dropped_backpressure
+                .fetch_add(rejected.len(), Ordering::Relaxed);
+            return;
+        }
+
+        let mut queue = locked(&self.queue);
+        rejected.append(&mut queue.take());
+        let mut rejected = SessionQueue::from_updates(rejected);
+        let overflow = rejected.len().saturating_sub(self.max_items);
+        if overflow > 0 {
+            rejected.drop_oldest(overflow);
+            self.stats
+                .dropped_backpressure
+                .fetch_add(overflow, Ordering::Relaxed);
+        }
+        self.limit_queue(&mut rejected);
+        *queue = rejected;
+    }
+
+    /// Drops the updates of the `queue` beyond `max_queued`, according to the
+    /// `drop_policy`.
+    ///
+    /// Returns the number of dropped updates.
+    fn limit_queue(&self, queue: &mut SessionQueue) -> usize {
+        let max_queued = match self.max_queued {
+            Some(max_queued) => max_queued,
+            None => return 0,
+        };
+        let overflow = queue.len().saturating_sub(max_queued);
+        if overflow == 0 {
+            return 0;
+        }
+        match self.drop_policy {
+            SessionDropPolicy::DropOldest => queue.drop_oldest(overflow),
+            SessionDropPolicy::DropNewest => queue.truncate(max_queued),
+        }
+        sentry_debug_if!(
+            self.debug,
+            "session queue is full; dropping {} session updates",
+            overflow
+        );
+        self.stats
+            .dropped_backpressure
+            .fetch_add(overflow, Ordering::Relaxed);
+        overflow
+    }
+
+    /// Writes `session_updates` to the `spool_dir`, if one is configured.
+    ///
+    /// Nothing is written once the `spool_dir` holds `MAX_SPOOL_FILES` files.
+    /// Returns `true` if the updates were spooled.
+    fn spool(&self, session_updates: &[SessionUpdate<'static>]) -> bool {
+        let spool_dir = match self.spool_dir {
+            Some(ref spool_dir) => spool_dir,
+            None => return false,
+        };
+        match spool_files(spool_dir) {
+            Ok(ref files) if files.len() >= MAX_SPOOL_FILES => {
+                sentry_debug_if!(self.debug, "session spool directory is full");
+                return false;
+            }
+            _ => {}
+        }
+        match write_spool_file(spool_dir, session_updates) {
+            Ok(()) => {
+                sentry_debug_if!(
+                    self.debug,
+                    "spooled {} session updates to {}",
+                    session_updates.len(),
+                    spool_dir.display()
+                );
+                true
+            }
+            Err(err) => {
+                sentry_debug_if!(self.debug, "failed to spool session updates: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Moves all updates that are still queued to the `spool_dir`.
+    ///
+    /// Without a `spool_dir`, or if spooling fails, the queue is left as is.
+    fn spool_queue(&self) {
+        let mut queue = locked(&self.queue);
+        if !queue.is_empty() && self.spool(&queue.updates) {
+            queue.clear();
+        }
+    }
+}
+
+/// The extension of spool files, which contain one session update per line.
+const SPOOL_EXTENSION: &str = "sessions";
+/// The extension of spool files that are being replayed by a client.
+const SPOOL_CLAIMED_EXTENSION: &str = "claimed";
+/// The maximum number of spool files kept in the spool directory.
+const MAX_SPOOL_FILES: usize = 64;
+/// The maximum total size of the spool files replayed by a single client.
+const MAX_SPOOL_REPLAY_BYTES: u64 = 1024 * 1024;
+
+/// Returns the paths of all complete spool files in `spool_dir`.
+fn spool_files(spool_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    Ok(fs::read_dir(spool_dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() == Some(SPOOL_EXTENSION.as_ref()))
+        .collect())
+}
+
+/// Writes `session_updates` into a new spool file in `spool_dir`.
+///
+/// The file is written under a temporary name and renamed once complete, so
+/// that a partially written file is never replayed.
+fn write_spool_file(
+    spool_dir: &Path,
+    session_updates: &[SessionUpdate<'static>],
+) -> io::Result<()> {
+    fs::create_dir_all(spool_dir)?;
+    let path = spool_dir.join(Uuid::new_v4().to_string());
+    let tmp_path = path.with_extension("tmp");
+    let mut file = BufWriter::new(fs::File::create(&tmp_path)?);
+    for session_update in session_updates {
+        serde_json::to_writer(&mut file, session_update)?;
+        file.write_all(b"\n")?;
+    }
+    file.flush()?;
+    drop(file);
+    fs::rename(&tmp_path, path.with_extension(SPOOL_EXTENSION))
+}
+
+/// Reads all session updates from a single spool file.
+fn read_spool_file(path: &Path) -> io::Result<Vec<SessionUpdate<'static>>> {
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(io::Error::from))
+        .collect()
+}
+
+/// Claims, reads and removes the spool files in `spool_dir`.
+///
+/// Each file is claimed by renaming it before it is read, so that clients
+/// sharing the directory never replay the same file twice.  At most
+/// `MAX_SPOOL_REPLAY_BYTES` are replayed, the remaining files are left for
+/// the next client.  Files that can not be read or parsed are skipped, and
+/// removed as well, so that a corrupt file does not fail every startup.
+fn take_spooled_updates(spool_dir: &Path, debug: bool) -> Vec<SessionUpdate<'static>> {
+    let paths = match spool_files(spool_dir) {
+        Ok(paths) => paths,
+        Err(_) => return Vec::new(),
+    };
+    let mut session_updates = Vec::new();
+    let mut replayed_bytes = 0;
+    for path in paths {
+        let size = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            // the file was claimed by another client in the meantime
+            Err(_) => continue,
+        };
+        if replayed_bytes > 0 && replayed_bytes + size > MAX_SPOOL_REPLAY_BYTES {
+            break;
+        }
+        let claimed_path = path.with_extension(SPOOL_CLAIMED_EXTENSION);
+        if fs::rename(&path, &claimed_path).is_err() {
+            continue;
+        }
+        replayed_bytes += size;
+        match read_spool_file(&claimed_path) {
+            Ok(mut spooled) => session_updates.append(&mut spooled),
+            Err(err) => {
+                sentry_debug_if!(
+                    debug,
+                    "skipping corrupt session spool file {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+        fs::remove_file(&claimed_path).ok();
+    }
+    sentry_debug_if!(
+        debug && !session_updates.is_empty(),
+        "replaying {} spooled session updates",
+        session_updates.len()
+    );
+    session_updates
+}
+
+/// The reason a session update was rejected by
+/// [`Client::enqueue_session`](struct.Client.html#method.enqueue_session).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionUpdateError {
+    /// Sessions are disabled via `SessionMode::Disabled`.
+    Disabled,
+    /// The `release` attribute is empty.
+    EmptyRelease,
+    /// The `duration` is negative, `NaN` or infinite.
+    InvalidDuration,
+    /// The `errors` count exceeds the `max_session_errors` option.
+    TooManyErrors,
+    /// There is no transport to send the session update, for example
+    /// because the client has no DSN or was closed.
+    TransportUnavailable,
+    /// The queue holds `max_queued_sessions` updates, and the
+    /// `session_queue_drop_policy` drops the newest one.
+    QueueFull,
+}
+
+impl fmt::Display for SessionUpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SessionUpdateError::Disabled => write!(f, "sessions are disabled"),
+            SessionUpdateError::EmptyRelease => write!(f, "the session release is empty"),
+            SessionUpdateError::InvalidDuration => write!(f, "the session duration is invalid"),
+            SessionUpdateError::TooManyErrors => write!(f, "the session has too many errors"),
+            SessionUpdateError::TransportUnavailable => {
+                write!(f, "no transport is available to send the session")
+            }
+            SessionUpdateError::QueueFull => write!(f, "the session queue is full"),
+        }
+    }
+}
+
+impl std::error::Error for SessionUpdateError {}
+
+/// Validates a session update that was created outside of the SDK.
+pub(crate) fn validate_session_update(
+    session_update: &SessionUpdate<'static>,
+    max_errors: u64,
+) -> Result<(), SessionUpdateError> {
+    if session_update.attributes.release.is_empty() {
+        return Err(SessionUpdateError::EmptyRelease);
+    }
+    if let Some(duration) = session_update.duration {
+        if !duration.is_finite() || duration < 0.0 {
+            return Err(SessionUpdateError::InvalidDuration);
+        }
+    }
+    if session_update.errors > max_errors.max(1) {
+        return Err(SessionUpdateError::TooManyErrors);
+    }
+    Ok(())
+}
+
+/// Turns a session duration in seconds into one that is valid for the server.
+///
+/// Negative, `NaN` and infinite durations are replaced by `0.0`.
+pub(crate) fn sanitize_duration(duration: f64) -> f64 {
+    if duration.is_finite() && duration > 0.0 {
+        duration
+    } else {
+        0.0
+    }
+}
+
+/// Assigns the next `sequence` number to an update of a session.
+///
+/// The numbers increase monotonically per session, starting at `0` for the
+/// initial update, which lets the server order the updates of a session.
+/// The server only ever keeps the update with the highest number, so the
+/// number saturates at `u64::MAX` instead of wrapping around, which would
+/// make all later updates of a very long-lived session appear outdated.
+fn advance_sequence(session_update: &mut SessionUpdate<'_>) {
+    session_update.sequence = Some(
+        session_update
+            .sequence
+            .map_or(0, |seq| seq.saturating_add(1)),
+    );
+}
+
+/// Estimates the serialized size of a session update in bytes.
+///
+/// This only sums up the lengths of the variable-sized fields, to avoid
+/// serializing each update twice.
+fn estimated_size(session_update: &SessionUpdate<'_>) -> usize {
+    let attributes = &session_update.attributes;
+    SESSION_UPDATE_BASE_SIZE
+        + session_update.distinct_id.as_ref().map_or(0, String::len)
+        + attributes.release.len()
+        + attributes.environment.as_ref().map_or(0, |env| env.len())
+        + attributes.user_agent.as_ref().map_or(0, String::len)
+        + attributes
+            .tags
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum::<usize>()
+}
+
+/// Coalesces consecutive updates of the same session into a single update.
+///
+/// Each update carries the complete state of its session, including the
+/// total `errors` count, so only the latest one is kept.  The `init` flag of
+/// a coalesced update is preserved, as the server needs it exactly once.
+fn coalesce_updates(queue: Vec<SessionUpdate<'static>>) -> Vec<SessionUpdate<'static>> {
+    let mut coalesced: Vec<SessionUpdate<'static>> = Vec::with_capacity(queue.len());
+    for session_update in queue {
+        match coalesced.last_mut() {
+            Some(last) if last.session_id == session_update.session_id => {
+                let init = last.init || session_update.init;
+                *last = session_update;
+                last.init = init;
+            }
+            _ => coalesced.push(session_update),
+        }
+    }
+    coalesced
+}
+
+/// Creates the flush callback that is handed to a `session_flush_scheduler`.
+///
+/// The callback only holds a weak reference, so it does not keep the flusher alive.
+fn scheduled_flush(state: &Arc<RwLock<Arc<FlusherState>>>) -> Box<dyn FnMut() + Send> {
+    let state = Arc::downgrade(state);
+    Box::new(move || {
+        let state = match state.upgrade() {
+            Some(state) => state.read().unwrap_or_else(PoisonError::into_inner).clone(),
+            None => return,
+        };
+        if let Some(signal) = state.handle_pending_signal() {
+            SignalHandler::reraise(signal);
+            return;
+        }
+        state.flush();
+    })
+}
+
+/// The background thread of the flusher, which is woken up to handle a signal.
+type SignalWakeup = Arc<(Mutex<bool>, Condvar)>;
+
+/// The handler that records the `FLUSH_SIGNALS` for the flusher.
+///
+/// The signal handler itself only records the signal and writes to a
+/// self-pipe.  A dedicated thread waits on the pipe and wakes up the
+/// background thread of the flusher, which then handles the signal.
+#[cfg(all(unix, feature = "session-signals"))]
+#[derive(Clone)]
+struct SignalHandler {
+    debug: bool,
+    pending: Arc<AtomicUsize>,
+    wakeup: Arc<Mutex<Option<SignalWakeup>>>,
+    registration: Arc<Mutex<Option<SignalRegistration>>>,
+}
+
+/// The registered handlers of an installed `SignalHandler`.
+#[cfg(all(unix, feature = "session-signals"))]
+struct SignalRegistration {
+    ids: Vec<signal_hook::SigId>,
+    // the write end of the self-pipe, to stop the signal thread
+    pipe: UnixStream,
+    closed: Arc<AtomicBool>,
+}
+
+#[cfg(all(unix, feature = "session-signals"))]
+impl SignalRegistration {
+    /// Unregisters the handlers, without restoring the default actions.
+    fn unregister(&self) {
+        for id in &self.ids {
+            signal_hook::unregister(*id);
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "session-signals"))]
+impl SignalHandler {
+    /// Installs the handler, which only records the received signal.
+    fn install(debug: bool) -> Self {
+        let handler = SignalHandler {
+            debug,
+            ..SignalHandler::none()
+        };
+        *locked(&handler.registration) = handler.register();
+        handler
+    }
+
+    /// Returns a handler that was not installed for any signals.
+    fn none() -> Self {
+        SignalHandler {
+            debug: false,
+            pending: Arc::new(AtomicUsize::new(0)),
+            wakeup: Arc::new(Mutex::new(None)),
+            registration: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Registers the signal handlers, and spawns the thread waiting on the self-pipe.
+    fn register(&self) -> Option<SignalRegistration> {
+        let result = UnixStream::pair().and_then(|(pipe, receiver)| {
+            let mut registration = SignalRegistration {
+                ids: vec![],
+                pipe,
+                closed: Arc::new(AtomicBool::new(false)),
+            };
+            // the signal is recorded before the pipe is written to
+            for signal in FLUSH_SIGNALS.iter().copied() {
+                let pending = self.pending.clone();
+                let registered =
+                    signal_hook::flag::register_usize(signal, pending, signal as usize).and_then(
+                        |id| {
+                            registration.ids.push(id);
+                            signal_hook::pipe::register(signal, registration.pipe.try_clone()?)
+                        },
+                    );
+                match registered {
+                    Ok(id) => registration.ids.push(id),
+                    Err(err) => {
+                        registration.unregister();
+                        return Err(err);
+                    }
+                }
+            }
+            let wakeup = self.wakeup.clone();
+            let closed = registration.closed.clone();
+            let spawned = std::thread::Builder::new()
+                .name("sentry-session-signals".into())
+                .spawn(move || wait_for_signals(receiver, &closed, &wakeup));
+            if let Err(err) = spawned {
+                registration.unregister();
+                return Err(err);
+            }
+            restore_default_actions();
+            INSTALLED_SIGNAL_HANDLERS.fetch_add(1, Ordering::SeqCst);
+            Ok(registration)
+        });
+        match result {
+            Ok(registration) => Some(registration),
+            Err(err) => {
+                sentry_debug_if!(
+                    self.debug,
+                    "failed to install the session signal handler: {}",
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    #[cfg(all(test, feature = "test"))]
+    fn is_installed(&self) -> bool {
+        locked(&self.registration).is_some()
+    }
+
+    /// Sets the background thread that is woken up when a signal is received.
+    fn set_wakeup(&self, wakeup: SignalWakeup) {
+        *locked(&self.wakeup) = Some(wakeup);
+    }
+
+    /// Returns the recorded signal, if any, and resets it.
+    fn take_pending(&self) -> Option<c_int> {
+        match self.pending.swap(0, Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal as c_int),
+        }
+    }
+
+    /// Unregisters the handler and stops the signal thread.
+    fn uninstall(&self) {
+        if let Some(registration) = locked(&self.registration).take() {
+            registration.unregister();
+            INSTALLED_SIGNAL_HANDLERS.fetch_sub(1, Ordering::SeqCst);
+            registration.closed.store(true, Ordering::SeqCst);
+            (&registration.pipe).write_all(&[0]).ok();
+        }
+    }
+
+    /// Installs the handler again in a forked child process.
+    ///
+    /// The signal thread does not exist in the child, and the self-pipe is
+    /// shared with the parent, so both are replaced.
+    fn reinit_after_fork(&self) {
+        let mut registration = match try_locked(&self.registration) {
+            Some(registration) => registration,
+            None => {
+                sentry_debug_if!(self.debug, "session signal handler is locked after fork");
+                return;
+            }
+        };
+        if let Some(previous) = registration.take() {
+            previous.unregister();
+            INSTALLED_SIGNAL_HANDLERS.fetch_sub(1, Ordering::SeqCst);
+            *registration = self.register();
+        }
+    }
+
+    /// Re-raises a `signal` with its default action, typically terminating the process.
+    fn reraise(signal: c_int) {
+        signal_hook::cleanup::cleanup_signal(signal).ok();
+        unsafe {
+            libc::raise(signal);
+        }
+    }
+}
+
+/// The number of installed session signal handlers.
+#[cfg(all(unix, feature = "session-signals"))]
+static INSTALLED_SIGNAL_HANDLERS: AtomicUsize = AtomicUsize::new(0);
+
+/// Registers actions that restore the default action of the `FLUSH_SIGNALS`
+/// once no session signal handler is installed anymore.
+///
+/// signal-hook does not restore the default action when the last action of
+/// a signal is unregistered, so the signals would be ignored from then on.
+#[cfg(all(unix, feature = "session-signals"))]
+fn restore_default_actions() {
+    static REGISTERED: Once = Once::new();
+    REGISTERED.call_once(|| {
+        for signal in FLUSH_SIGNALS.iter().copied() {
+            let action = move || {
+                if INSTALLED_SIGNAL_HANDLERS.load(Ordering::SeqCst) == 0 {
+                    signal_hook::cleanup::cleanup_raw(signal);
+                    // the signal is delivered again once this handler returns
+                    unsafe {
+                        libc::raise(signal);
+                    }
+                }
+            };
+            // the action only uses async-signal-safe functions
+            unsafe { signal_hook::register(signal, action) }.ok();
+        }
+    });
+}
+
+/// Waits for signals on the self-pipe `receiver`, and wakes up the flusher
+/// thread for each of them, until the handler is `closed`.
+#[cfg(all(unix, feature = "session-signals"))]
+fn wait_for_signals(
+    mut receiver: UnixStream,
+    closed: &AtomicBool,
+    wakeup: &Mutex<Option<SignalWakeup>>,
+) {
+    let mut buf = [0; 16];
+    loop {
+        match receiver.read(&mut buf) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        }
+        if closed.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Some(ref wakeup) = *locked(wakeup) {
+            let (lock, cvar) = wakeup.as_ref();
+            // taking the lock makes sure the thread is either waiting, or
+            // has not yet checked for pending signals
+            let _guard = locked(lock);
+            cvar.notify_one();
+        }
+    }
+}
+
+/// Without the `session-signals` feature, or outside of unix, no signals are handled.
+#[cfg(not(all(unix, feature = "session-signals")))]
+#[derive(Clone)]
+struct SignalHandler;
+
+#[cfg(not(all(unix, feature = "session-signals")))]
+impl SignalHandler {
+    fn install(debug: bool) -> Self {
+        sentry_debug_if!(
+            debug,
+            "the session signal handler requires the `session-signals` feature on unix"
+        );
+        SignalHandler
+    }
+
+    fn none() -> Self {
+        SignalHandler
+    }
+
+    fn set_wakeup(&self, _wakeup: SignalWakeup) {}
+
+    fn take_pending(&self) -> Option<c_int> {
+        None
+    }
+
+    fn uninstall(&self) {}
+
+    fn reinit_after_fork(&self) {}
+
+    fn reraise(_signal: c_int) {}
+}
+
+/// Returns the delay until the next retry after `failed_flushes` consecutive failed flushes.
+///
+/// The delay starts at `MIN_FLUSH_INTERVAL` and doubles with each failed
+/// flush, but it never exceeds the regular flush `interval`.
+fn retry_backoff(failed_flushes: usize, interval: Duration) -> Duration {
+    let exponent = failed_flushes
+        .saturating_sub(1)
+        .min(MAX_RETRY_BACKOFF_EXPONENT);
+    (MIN_FLUSH_INTERVAL * (1 << exponent)).min(interval)
+}
+
+/// Randomizes the flush `interval` by up to the given `jitter` fraction.
+///
+/// The `jitter` is capped at `1.0`, and the interval never drops below
+/// `MIN_FLUSH_INTERVAL`.
+fn jittered_interval(interval: Duration, jitter: f32) -> Duration {
+    if jitter <= 0.0 {
+        return interval;
+    }
+    let jitter = jitter.min(1.0);
+    let factor = 1.0 + jitter * (random::<f32>() * 2.0 - 1.0);
+    interval.mul_f32(factor).max(MIN_FLUSH_INTERVAL)
+}
+
+/// A builder for a `SessionFlusher`.
+///
+/// The flusher is configured from the `ClientOptions`, and individual
+/// settings can be overridden on top of them, regardless of whether the
+/// options are set before or after the overrides.
+pub(crate) struct SessionFlusherBuilder<'a> {
+    transport: TransportArc,
+    event_transport: Option<TransportArc>,
+    options: Cow<'a, ClientOptions>,
+    flush_interval: Option<Duration>,
+    flush_jitter: Option<f32>,
+    max_items: Option<usize>,
+    max_queued: Option<(Option<usize>, SessionDropPolicy)>,
+    spawn_thread: SpawnThread,
+}
+
+impl<'a> SessionFlusherBuilder<'a> {
+    /// Configures the flusher from the session settings of the `options`.
+    ///
+    /// Settings that were overridden explicitly keep their value.
+    pub fn options(self, options: &ClientOptions) -> SessionFlusherBuilder<'_> {
+        SessionFlusherBuilder {
+            transport: self.transport,
+            event_transport: self.event_transport,
+            options: Cow::Borrowed(options),
+            flush_interval: self.flush_interval,
+            flush_jitter: self.flush_jitter,
+            max_items: self.max_items,
+            max_queued: self.max_queued,
+            spawn_thread: self.spawn_thread,
+        }
+    }
+
+    /// Sets the transport of the events, which is shut down together with the
+    /// session transport when a signal is handled.
+    pub fn event_transport(mut self, event_transport: TransportArc) -> Self {
+        self.event_transport = Some(event_transport);
+        self
+    }
+
+    /// Sets the interval in which queued updates are flushed.
+    #[allow(unused)]
+    pub fn flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.flush_interval = Some(flush_interval);
+        self
+    }
+
+    /// Sets the jitter applied to the flush interval.
+    #[allow(unused)]
+    pub fn flush_jitter(mut self, flush_jitter: f32) -> Self {
+        self.flush_jitter = Some(flush_jitter);
+        self
+    }
+
+    /// Sets the maximum number of session updates sent in a single envelope.
+    #[allow(unused)]
+    pub fn max_items(mut self, max_items: usize) -> Self {
+        self.max_items = Some(max_items);
+        self
+    }
+
+    /// Bounds the queue to `max_queued` updates, dropping updates beyond it
+    /// according to the `drop_policy`.
+    #[allow(unused)]
+    pub fn max_queued(mut self, max_queued: usize, drop_policy: SessionDropPolicy) -> Self {
+        self.max_queued = Some((Some(max_queued), drop_policy));
+        self
+    }
+
+    /// Replaces how the background thread is spawned, to make spawning fail.
+    #[cfg(all(test, feature = "test"))]
+    pub fn spawn_thread(mut self, spawn_thread: SpawnThread) -> Self {
+        self.spawn_thread = spawn_thread;
+        self
+    }
+
+    /// Creates the flusher, which starts flushing right away.
+    pub fn build(self) -> SessionFlusher {
+        let transport = self.transport;
+        let options = &*self.options;
+        let flush_interval = self
+            .flush_interval
+            .unwrap_or(options.session_flush_interval);
+        let flush_jitter = self.flush_jitter.unwrap_or(options.session_flush_jitter);
+        let max_items = self.max_items.unwrap_or(options.max_session_items).max(1);
+        let (max_queued, drop_policy) = self.max_queued.unwrap_or((
+            options.max_queued_sessions,
+            options.session_queue_drop_policy,
+        ));
+        if max_items > MAX_SESSION_ITEMS {
+            sentry_debug_if!(
+                options.debug,
+                "max_session_items of {} exceeds the documented limit of {} items per envelope",
+                max_items,
+                MAX_SESSION_ITEMS
+            );
+        }
+        let scheduled = options.session_flush_scheduler.is_some();
+        // the signals are handled by the background thread or the scheduler,
+        // a synchronous flusher would leave them unhandled
+        let signals = if options.install_session_signal_handler
+            && (scheduled || !options.synchronous_session_flush)
+        {
+            SignalHandler::install(options.debug)
+        } else {
+            SignalHandler::none()
+        };
+        let state = Arc::new(FlusherState {
+            transport,
+            queue: Mutex::new(SessionQueue::default()),
+            before_send: options.before_send_session.clone(),
+            stats: FlusherStats::default(),
+            max_items,
+            max_bytes: options.max_session_envelope_bytes,
+            max_queued: max_queued.map(|max_queued| max_queued.max(1)),
+            drop_policy,
+            flush_interval: flush_interval.max(MIN_FLUSH_INTERVAL),
+            flush_jitter,
+            flush_debounce: options.session_flush_debounce,
+            queued_at: Mutex::new(None),
+            debug: options.debug,
+            disabled: options.session_mode == SessionMode::Disabled,
+            dry_run: options.session_dry_run,
+            flush_on_drop: AtomicBool::new(options.session_flush_on_drop),
+            thread_name: match options.session_flusher_thread_prefix {
+                Some(ref prefix) => format!("{}-sentry-session-flusher", prefix),
+                None => "sentry-session-flusher".into(),
+            },
+            stack_size: options.session_flusher_stack_size,
+            max_retries: options.max_session_flush_retries,
+            event_transport: self.event_transport,
+            shutdown_timeout: options.shutdown_timeout,
+            spawn_thread: self.spawn_thread,
+            signals,
+            failed_flushes: AtomicUsize::new(0),
+            spool_dir: options.session_spool_dir.clone(),
+            clock: options
+                .session_clock
+                .clone()
+                .unwrap_or_else(|| Arc::new(SystemClock)),
+            recent_flushes: Mutex::new(VecDeque::with_capacity(RECENT_FLUSH_METRICS)),
+            open_sessions: Mutex::new(HashMap::new()),
+        });
+        let worker = if scheduled || options.synchronous_session_flush {
+            None
+        } else {
+            FlusherWorker::try_spawn(state.clone())
+        };
+        if worker.is_none() && !scheduled {
+            state.signals.uninstall();
+        }
+        let flush_interval = state.flush_interval;
+        let state = Arc::new(RwLock::new(state));
+        if let Some(ref scheduler) = options.session_flush_scheduler {
+            scheduler(flush_interval, scheduled_flush(&state));
+        }
+
+        SessionFlusher {
+            state,
+            threadless: worker.is_none(),
+            worker: Mutex::new(worker),
+        }
+    }
+}
+
+/// Background Session Flusher
+///
+/// The background flusher queues session updates for delayed batched sending.
+/// It has its own background thread that will flush its queue once every
+/// `session_flush_interval`, as configured in the `ClientOptions`, unless a
+/// `session_flush_scheduler` drives the flushing instead, or flushing is
+/// configured to be synchronous.
+///
+/// For now it just batches all the session updates together into one envelope,
+/// but in the future it will also pre-aggregate session numbers.
+///
+/// # Concurrency
+///
+/// The flusher is shared by all hubs bound to its client, and session updates
+/// are enqueued concurrently from any thread.  The queue lock is never held
+/// while calling into the transport, so enqueueing does not block on a flush
+/// in progress for longer than it takes to swap out the queue.  The
+/// background thread holds its own lock while flushing, so the queue lock
+/// must be released before waking it up.
+pub(crate) struct SessionFlusher {
+    // the state is only ever replaced in a forked child process
+    state: Arc<RwLock<Arc<FlusherState>>>,
+    worker: Mutex<Option<FlusherWorker>>,
+    // whether flushing happens without a background thread
+    threadless: bool,
+}
+
+/// Spawns the background thread of the flusher from the thread `Builder`.
+type SpawnThread = fn(std::thread::Builder, Box<dyn FnOnce() + Send>) -> io::Result<JoinHandle<()>>;
+
+fn spawn_thread(
+    builder: std::thread::Builder,
+    f: Box<dyn FnOnce() + Send>,
+) -> io::Result<JoinHandle<()>> {
+    builder.spawn(f)
+}
+
+/// The background thread of the flusher.
+struct FlusherWorker {
+    shutdown: Arc<(Mutex<bool>, Condvar)>,
+    handle: JoinHandle<()>,
+    // the sender is dropped once the thread is done, which signals `shutdown`
+    done: Receiver<()>,
+}
+
+impl FlusherWorker {
+    /// Spawns the background thread, or returns `None` if that fails.
+    ///
+    /// The flusher then falls back to flushing synchronously.
+    fn try_spawn(state: Arc<FlusherState>) -> Option<Self> {
+        let debug = state.debug;
+        match Self::spawn(state) {
+            Ok(worker) => Some(worker),
+            Err(err) => {
+                sentry_debug_if!(
+                    debug,
+                    "failed to spawn the session flusher thread, flushing synchronously: {}",
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    fn spawn(state: Arc<FlusherState>) -> io::Result<Self> {
+        #[allow(clippy::mutex_atomic)]
+        let shutdown = Arc::new((Mutex::new(false), Condvar::new()));
+        let worker_shutdown = shutdown.clone();
+        state.signals.set_wakeup(shutdown.clone());
+        let (done_sender, done) = channel();
+        let mut builder = std::thread::Builder::new().name(state.thread_name.clone());
+        if let Some(stack_size) = state.stack_size {
+            builder = builder.stack_size(stack_size);
+        }
+        let spawn_thread = state.spawn_thread;
+        let handle = spawn_thread(
+            builder,
+            Box::new(move || {
+                let _done_sender = done_sender;
+                let (lock, cvar) = worker_shutdown.as_ref();
+                let mut shutdown = lock.lock().unwrap();
+                let mut last_flush = Instant::now();
+                let mut interval = jittered_interval(state.flush_interval, state.flush_jitter);
+                // check this immediately, in case the main thread is already shutting down
+                while !*shutdown {
+                    // the signal thread takes the lock before waking us up,
+                    // so a signal can not arrive unnoticed before we wait
+                    if let Some(signal) = state.handle_pending_signal() {
+                        drop(shutdown);
+                        SignalHandler::reraise(signal);
+                        return;
+                    }
+                    let mut timeout = interval
+                        .checked_sub(last_flush.elapsed())
+                        .unwrap_or_default();
+                    if let Some(debounce) = state.debounce_remaining() {
+                        timeout = timeout.min(debounce);
+                    }
+                    shutdown = cvar.wait_timeout(shutdown, timeout).unwrap().0;
+                    let due = last_flush.elapsed() >= interval
+                        || state.debounce_remaining() == Some(Duration::default());
+                    if *shutdown || !due {
+                        continue;
+                    }
+                    state.flush_catching();
+                    last_flush = Instant::now();
+                    // retry rejected updates sooner, backing off exponentially
+                    interval = match state.failed_flushes.load(Ordering::Relaxed) {
+                        0 => jittered_interval(state.flush_interval, state.flush_jitter),
+                        failed_flushes => retry_backoff(failed_flushes, state.flush_interval),
+                    };
+                }
+                drop(shutdown);
+                // send out everything that was queued up until the shutdown
+                state.flush_catching();
+            }),
+        )?;
+
+        Ok(Self {
+            shutdown,
+            handle,
+            done,
+        })
+    }
+
+    fn signal_shutdown(&self) {
+        let (lock, cvar) = self.shutdown.as_ref();
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+    }
+
+    /// Whether the thread is still running.
+    fn is_alive(&self) -> bool {
+        !matches!(self.done.try_recv(), Err(TryRecvError::Disconnected))
+    }
+
+    /// Wakes up the thread, so that it re-evaluates when to flush next.
+    fn wake(&self) {
+        let (lock, cvar) = self.shutdown.as_ref();
+        // taking the lock makes sure the thread is either waiting, or has not
+        // yet computed its next timeout, so the wakeup can not get lost.
+        let _guard = lock.lock().unwrap();
+        cvar.notify_one();
+    }
+}
+
+impl SessionFlusher {
+    /// Creates a builder for a Flusher that will submit envelopes to the given `transport`.
+    pub fn builder(transport: TransportArc) -> SessionFlusherBuilder<'static> {
+        SessionFlusherBuilder {
+            transport,
+            event_transport: None,
+            options: Cow::Owned(ClientOptions::default()),
+            flush_interval: None,
+            flush_jitter: None,
+            max_items: None,
+            max_queued: None,
+            spawn_thread,
+        }
+    }
+
+    /// Returns the current state of the flusher.
+    fn state(&self) -> Arc<FlusherState> {
+        self.state
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Enqueues a session update for delayed sending.
+    ///
+    /// When the queue is full, it will be flushed immediately.  The queue is
+    /// also flushed right before the update would make it exceed the
+    /// `max_session_envelope_bytes` budget.  With a `session_flush_debounce`,
+    /// the background thread is woken up when the queue becomes non-empty.
+    /// With `SessionMode::Disabled`, session updates are discarded.
+    ///
+    /// Once the queue holds `max_queued_sessions` updates, either the oldest
+    /// or the new update is dropped.  Returns `false` if the new update was
+    /// dropped this way.
+    pub fn enqueue(&self, session_update: SessionUpdate<'static>) -> bool {
+        let state = self.state();
+        if state.disabled {
+            return true;
+        }
+        self.revive_worker();
+        let size = estimated_size(&session_update);
+        let mut queue = locked(&state.queue);
+        if !queue.is_empty() && queue.bytes + size > state.max_bytes {
+            drop(queue);
+            state.flush();
+            queue = locked(&state.queue);
+        }
+        let was_empty = queue.is_empty();
+        queue.push(session_update);
+        let dropped = state.limit_queue(&mut queue) > 0;
+        if queue.len() >= state.max_items {
+            drop(queue);
+            state.flush();
+        } else if was_empty && state.flush_debounce.is_some() {
+            self.debounce_flush(queue);
+        }
+        !(dropped && state.drop_policy == SessionDropPolicy::DropNewest)
+    }
+
+    /// Enqueues the session updates spooled by previous clients.
+    ///
+    /// Spooled updates are only replayed when they can actually be sent.  They
+    /// are enqueued like any other updates, so they count towards the
+    /// `max_queued_sessions`.
+    pub fn replay_spooled(&self) {
+        let state = self.state();
+        let spool_dir = match state.spool_dir {
+            Some(ref spool_dir) if !state.disabled && self.has_transport() => spool_dir,
+            _ => return,
+        };
+        self.enqueue_many(take_spooled_updates(spool_dir, state.debug));
+    }
+
+    /// Enqueues multiple session updates at once.
+    ///
+    /// The queue is locked only once for all the updates.  When the queue
+    /// then holds more than `max_session_items` updates, or exceeds the
+    /// `max_session_envelope_bytes` budget, it is flushed immediately, which
+    /// splits the updates into as many envelopes as needed.
+    pub fn enqueue_many(&self, session_updates: Vec<SessionUpdate<'static>>) {
+        let state = self.state();
+        if state.disabled || session_updates.is_empty() {
+            return;
+        }
+        self.revive_worker();
+        let mut queue = locked(&state.queue);
+        let was_empty = queue.is_empty();
+        for session_update in session_updates {
+            queue.push(session_update);
+        }
+        state.limit_queue(&mut queue);
+        if queue.len() >= state.max_items || queue.bytes > state.max_bytes {
+            drop(queue);
+            state.flush();
+        } else if was_empty && state.flush_debounce.is_some() {
+            self.debounce_flush(queue);
+        }
+    }
+
+    /// Restarts the background thread if it died unexpectedly.
+    ///
+    /// Panics while flushing are caught, so this only happens when the
+    /// thread itself failed.  Without a restart, queued session updates
+    /// would no longer be flushed periodically.
+    fn revive_worker(&self) {
+        let state = self.state();
+        let mut worker = locked(&self.worker);
+        match *worker {
+            Some(ref current) if !current.is_alive() => {}
+            _ => return,
+        }
+        sentry_debug_if!(state.debug, "session flusher thread died, restarting it");
+        *worker = FlusherWorker::try_spawn(state.clone());
+        if worker.is_none() {
+            state.signals.uninstall();
+        }
+    }
+
+    /// Records when the first update was put into the `queue`, and wakes up
+    /// the background thread to flush it after the `session_flush_debounce`.
+    fn debounce_flush(&self, queue: MutexGuard<'_, SessionQueue>) {
+        *locked(&self.state().queued_at) = Some(Instant::now());
+        drop(queue);
+        if let Some(ref worker) = *locked(&self.worker) {
+            worker.wake();
+        }
+    }
+
+    /// Records the last sent state of a session that was not closed yet.
+    pub fn track(&self, session_update: &SessionUpdate<'static>, started: Instant) {
+        locked(&self.state().open_sessions)
+            .insert(session_update.session_id, (session_update.clone(), started));
+    }
+
+    /// Passes a session update that is sent right away, without going through
+    /// the queue, through the `before_send_session` callback.
+    pub fn before_send(
+        &self,
+        session_update: SessionUpdate<'static>,
+    ) -> Option<SessionUpdate<'static>> {
+        self.state().before_send(session_update)
+    }
+
+    /// Forgets about a session once it was closed.
+    pub fn untrack(&self, session_id: &Uuid) {
+        locked(&self.state().open_sessions).remove(session_id);
+    }
+
+    /// Immediately flushes all the queued session updates.
+    ///
+    /// This can safely be called concurrently with the background thread.
+    /// Returns `true` if any session update was sent to the transport.
+    pub fn flush_now(&self) -> bool {
+        self.state().flush()
+    }
+
+    /// Flushes the queue and releases its memory, in response to memory pressure.
+    ///
+    /// Updates that the transport rejects are dropped instead of being kept
+    /// for a retry, and are counted as dropped because of backpressure.
+    /// Returns `true` if any session update was sent to the transport.
+    pub fn flush_on_pressure(&self) -> bool {
+        let state = self.state();
+        // updates that are enqueued while sending are kept in the queue
+        let (sent, rejected) = match state.send_queue() {
+            Some(flushed) => flushed,
+            None => return false,
+        };
+        let rejected = rejected.len();
+        if rejected > 0 {
+            sentry_debug_if!(
+                state.debug,
+                "dropping {} session updates because of memory pressure",
+                rejected
+            );
+            state
+                .stats
+                .dropped_backpressure
+                .fetch_add(rejected, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Returns the number of session updates that were sent or dropped so far.
+    pub fn stats(&self) -> SessionFlusherStats {
+        let state = self.state();
+        let stats = &state.stats;
+        SessionFlusherStats {
+            sent: stats.sent.load(Ordering::Relaxed),
+            dropped_no_transport: stats.dropped_no_transport.load(Ordering::Relaxed),
+            dropped_backpressure: stats.dropped_backpressure.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether a transport is available to send session updates.
+    pub fn has_transport(&self) -> bool {
+        self.state().transport.read().unwrap().is_some()
+    }
+
+    /// Returns the number of individual session updates and of aggregate
+    /// groups that are queued for sending.
+    ///
+    /// Session updates are never aggregated, so the number of aggregate
+    /// groups is always `0`.
+    pub fn pending_len(&self) -> (usize, usize) {
+        (locked(&self.state().queue).len(), 0)
+    }
+
+    /// Returns the metrics of the most recent flushes, oldest first.
+    pub fn recent_flush_metrics(&self) -> Vec<SessionFlushMetrics> {
+        locked(&self.state().recent_flushes)
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Shuts down the background thread, which does a final flush.
+    ///
+    /// Sessions that were started but never closed are reported as `Abnormal`
+    /// as part of the final flush.  This waits at most for the given
+    /// `timeout` and returns `true` if the final flush completed in time.
+    /// When the timeout is exceeded, the background thread is detached and
+    /// will finish on its own.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let state = self.state();
+        // nothing handles the signals once the background thread is gone
+        state.signals.uninstall();
+        state.abandon_open_sessions();
+
+        let worker = match locked(&self.worker).take() {
+            Some(worker) => worker,
+            None => {
+                // without a background thread, the final flush happens right here
+                state.flush();
+                state.spool_queue();
+                return true;
+            }
+        };
+        worker.signal_shutdown();
+        match worker.done.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                sentry_debug_if!(state.debug, "session flusher did not shut down in time");
+                false
+            }
+            _ => {
+                worker.handle.join().ok();
+                state.spool_queue();
+                true
+            }
+        }
+    }
+
+    /// Sets whether the queued updates are flushed when the flusher is dropped.
+    pub fn set_flush_on_drop(&self, flush_on_drop: bool) {
+        self.state()
+            .flush_on_drop
+            .store(flush_on_drop, Ordering::Relaxed);
+    }
+
+    /// Restarts the background thread in a forked child process.
+    ///
+    /// The background thread of the parent does not exist in the child, so a
+    /// new one is spawned.  Session updates that were queued by the parent are
+    /// discarded, as the parent is responsible for sending them, and must not
+    /// be sent twice.  When flushing is driven by a `session_flush_scheduler`,
+    /// or is synchronous, no thread is spawned.
+    ///
+    /// The child no longer flushes its queue when the flusher is dropped, see
+    /// `set_flush_on_drop`.  Locks that were held by other threads of the
+    /// parent at the time of the fork are never released in the child, so
+    /// this never blocks on them.
+    /// When the queue or the open sessions are locked, the whole state is
+    /// replaced instead.
+    pub fn reinit_after_fork(&self) {
+        let mut state = self.state();
+        state.flush_on_drop.store(false, Ordering::Relaxed);
+        let cleared = match (try_locked(&state.queue), try_locked(&state.open_sessions)) {
+            (Some(mut queue), Some(mut open_sessions)) => {
+                queue.clear();
+                open_sessions.clear();
+                true
+            }
+            _ => false,
+        };
+        if !cleared {
+            let mut current = match self.state.try_write() {
+                Ok(current) => current,
+                Err(TryLockError::Poisoned(err)) => err.into_inner(),
+                Err(TryLockError::WouldBlock) => {
+                    sentry_debug_if!(state.debug, "session flusher is locked after fork");
+                    return;
+                }
+            };
+            sentry_debug_if!(
+                state.debug,
+                "replacing the locked session flusher after fork"
+            );
+            state = Arc::new(state.forked());
+            *current = state.clone();
+        }
+        state.signals.reinit_after_fork();
+        if self.threadless {
+            return;
+        }
+
+        let mut current = match try_locked(&self.worker) {
+            Some(current) => current,
+            None => {
+                sentry_debug_if!(state.debug, "session flusher thread is locked after fork");
+                return;
+            }
+        };
+        let worker = FlusherWorker::try_spawn(state.clone());
+        if worker.is_none() {
+            state.signals.uninstall();
+        }
+        if let Some(previous) = mem::replace(&mut *current, worker) {
+            // the thread is gone after a fork, but it would still be around
+            // when called without forking.  The shutdown lock might have been
+            // held by the thread at the time of the fork, so we must not block.
+            let (lock, cvar) = previous.shutdown.as_ref();
+            if let Ok(mut shutdown) = lock.try_lock() {
+                *shutdown = true;
+                cvar.notify_one();
+            }
+        }
+    }
+}
+
+impl Drop for SessionFlusher {
+    fn drop(&mut self) {
+        let state = self.state();
+        if !state.flush_on_drop.load(Ordering::Relaxed) {
+            // the pending updates are left to someone else, like the parent
+            // of a forked process, so neither the thread nor we send them
+            locked(&state.queue).clear();
+            locked(&state.open_sessions).clear();
+        }
+        if let Some(worker) = locked(&self.worker).take() {
+            worker.signal_shutdown();
+            worker.handle.join().ok();
+        }
+        state.signals.uninstall();
+        state.abandon_open_sessions();
+        self.flush_now();
+        state.spool_queue();
+    }
+}
+
+#[cfg(all(test, feature = "test"))]
+mod tests {
+    use super::*;
+    use crate as sentry;
+    use crate::protocol::{Envelope, EnvelopeItem, SessionStatus};
+
+    fn capture_envelopes<F>(f: F) -> Vec<Envelope>
+    where
+        F: FnOnce(),
+    {
+        crate::test::with_captured_envelopes_options(
+            f,
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_flush_and_capture_sessions() {
+        let envelopes = crate::test::flush_and_capture_sessions(|| {
+            sentry::capture_message("not in a session", sentry::Level::Info);
+            sentry::start_session();
+            sentry::start_session();
+        });
+        // the event without a session update is not returned
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 2);
+    }
+
+    #[test]
+    fn test_session_startstop() {
+        let clock = crate::test::TestClock::new();
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+                clock.advance(Duration::from_millis(1250));
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                session_clock: Some(clock.clone()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(envelopes.len(), 1);
+
+        let mut items = envelopes[0].items();
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Exited);
+            assert_eq!(session.duration, Some(1.25));
+            assert_eq!(
+                session.started,
+                clock.now_utc() - chrono::Duration::milliseconds(1250)
+            );
+            assert_eq!(session.timestamp, Some(clock.now_utc()));
+            assert_eq!(session.errors, 0);
+            assert_eq!(session.attributes.release, "some-release");
+            assert_eq!(session.init, true);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
+    #[test]
+    fn test_session_fallback_release() {
+        let envelopes = crate::test::with_captured_envelopes(|| {
+            sentry::start_session();
+        });
+        assert!(envelopes.is_empty());
+
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+            },
+            crate::ClientOptions {
+                require_release_for_sessions: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(envelopes.len(), 1);
+
+        let mut items = envelopes[0].items();
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Exited);
+            assert_eq!(session.attributes.release, FALLBACK_RELEASE);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
+    #[test]
+    fn test_session_disabled() {
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+                let err = "NaN".parse::<usize>().unwrap_err();
+                sentry::capture_error(&err);
+                sentry::end_session();
+
+                sentry::start_session();
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                session_mode: SessionMode::Disabled,
+                ..Default::default()
+            },
+        );
+        // only the error event is sent, without any session
+        assert_eq!(envelopes.len(), 1);
+
+        let mut items = envelopes[0].items();
+        assert!(matches!(items.next(), Some(EnvelopeItem::Event(_))));
+        assert_eq!(items.next(), None);
+    }
+
+    #[test]
+    fn test_session_ip_address() {
+        let ip_session = |send_default_pii| {
+            let envelopes = crate::test::with_captured_envelopes_options(
+                || {
+                    sentry::configure_scope(|scope| {
+                        scope.set_user(Some(User {
+                            id: Some("foo-bar".into()),
+                            ip_address: Some("127.0.0.1".parse().unwrap()),
+                            ..Default::default()
+                        }))
+                    });
+                    sentry::start_session();
+                },
+                crate::ClientOptions {
+                    release: Some("some-release".into()),
+                    send_default_pii,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(envelopes.len(), 1);
+            match envelopes[0].items().next() {
+                Some(EnvelopeItem::SessionUpdate(session)) => session.attributes.ip_address,
+                _ => panic!("expected session"),
+            }
+        };
+
+        assert_eq!(ip_session(false), None);
+        assert_eq!(ip_session(true), Some("127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_session_not_started_warning() {
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+                assert_eq!(sentry::current_session(), None);
+            },
+            crate::ClientOptions::default(),
+        );
+        assert!(envelopes.is_empty());
+
+        // the warning is emitted without the `debug` option, but only once
+        let warned = AtomicBool::new(false);
+        assert!(warn_session_not_started(&warned, false, "no release"));
+        assert!(!warn_session_not_started(&warned, false, "no release"));
+        assert!(!warn_session_not_started(&warned, true, "no release"));
+    }
+
+    #[test]
+    fn test_check_session_options() {
+        let check = |release: Option<&'static str>, session_mode| {
+            check_session_options(&crate::ClientOptions {
+                release: release.map(Into::into),
+                session_mode,
+                auto_session_tracking: true,
+                ..Default::default()
+            })
+        };
+        assert!(!check(None, SessionMode::Application));
+        assert!(check(Some("some-release"), SessionMode::Application));
+        assert!(check(None, SessionMode::Disabled));
+        assert!(check_session_options(&crate::ClientOptions {
+            require_release_for_sessions: false,
+            auto_session_tracking: true,
+            ..Default::default()
+        }));
+        // clients that do not request sessions are fine without a release
+        assert!(check_session_options(&crate::ClientOptions::default()));
+    }
+
+    #[test]
+    fn test_configure_session() {
+        let configured_session = |send_default_pii| {
+            let envelopes = crate::test::with_captured_envelopes_options(
+                || {
+                    sentry::start_session();
+                    sentry::configure_session(|session| {
+                        session.set_ip_address(Some("127.0.0.1".parse().unwrap()));
+                        session.set_user_agent(Some("Mozilla/5.0".into()));
+                    });
+                },
+                crate::ClientOptions {
+                    release: Some("some-release".into()),
+                    send_default_pii,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(envelopes.len(), 1);
+            match envelopes[0].items().next() {
+                Some(EnvelopeItem::SessionUpdate(session)) => session.attributes.clone(),
+                _ => panic!("expected session"),
+            }
+        };
+
+        let attributes = configured_session(false);
+        assert_eq!(attributes.ip_address, None);
+        assert_eq!(attributes.user_agent.as_deref(), Some("Mozilla/5.0"));
+
+        let attributes = configured_session(true);
+        assert_eq!(attributes.ip_address, Some("127.0.0.1".parse().unwrap()));
+        assert_eq!(attributes.user_agent.as_deref(), Some("Mozilla/5.0"));
+    }
+
+    #[test]
+    fn test_configure_session_reentrant() {
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+            let session_id = sentry::current_session().unwrap().session_id;
+            sentry::configure_session(|session| {
+                // the hub can be used from within the function
+                sentry::configure_scope(|scope| scope.set_tag("configured", "yes"));
+                session.set_user_agent(Some("Mozilla/5.0".into()));
+            });
+            assert_eq!(sentry::current_session().unwrap().session_id, session_id);
+        });
+        assert_eq!(envelopes.len(), 1);
+        match envelopes[0].items().next() {
+            Some(EnvelopeItem::SessionUpdate(session)) => {
+                assert_eq!(
+                    session.attributes.user_agent.as_deref(),
+                    Some("Mozilla/5.0")
+                );
+            }
+            _ => panic!("expected session"),
+        }
+    }
+
+    #[test]
+    fn test_session_os_user_agent() {
+        use crate::protocol::OsContext;
+
+        let os_session = |send_default_pii| {
+            let envelopes = crate::test::with_captured_envelopes_options(
+                || {
+                    sentry::configure_scope(|scope| {
+                        scope.set_context(
+                            "os",
+                            Context::Os(Box::new(OsContext {
+                                name: Some("Android".into()),
+                                version: Some("11".into()),
+                                ..Default::default()
+                            })),
+                        )
+                    });
+                    sentry::start_session();
+                },
+                crate::ClientOptions {
+                    release: Some("some-release".into()),
+                    send_default_pii,
+                    ..Default::default()
+                },
+            );
+            assert_eq!(envelopes.len(), 1);
+            match envelopes[0].items().next() {
+                Some(EnvelopeItem::SessionUpdate(session)) => session.attributes.user_agent.clone(),
+                _ => panic!("expected session"),
+            }
+        };
+
+        assert_eq!(os_session(false), None);
+        assert_eq!(os_session(true), Some("Android/11".into()));
+    }
+
+    #[test]
+    fn test_with_session_panic() {
+        let envelopes = capture_envelopes(|| {
+            let result = std::panic::catch_unwind(|| {
+                sentry::with_session(|| {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    panic!("oh no");
+                })
+            });
+            assert!(result.is_err());
+        });
+        assert_eq!(envelopes.len(), 1);
+
+        let mut items = envelopes[0].items();
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Exited);
+            assert!(session.duration.unwrap() > 0.01);
+            assert!(session.init);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
+    #[test]
+    fn test_with_session_nested() {
+        let mut sessions = None;
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+            let outer = sentry::current_session().unwrap().session_id;
+            let inner = sentry::with_session(|| sentry::current_session().unwrap().session_id);
+            assert_ne!(inner, outer);
+            // the outer session is still active after the inner one ended
+            assert_eq!(sentry::current_session().unwrap().session_id, outer);
+            sentry::end_session();
+            sessions = Some((outer, inner));
+        });
+        let (outer, inner) = sessions.unwrap();
+
+        let updates: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => (session.session_id, session.status),
+                _ => panic!("expected session"),
+            })
+            .collect();
+        assert_eq!(
+            updates,
+            vec![
+                (inner, SessionStatus::Exited),
+                (outer, SessionStatus::Exited)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session_dry_run() {
+        let transport = crate::test::TestTransport::new();
+        let hook_calls = Arc::new(AtomicUsize::new(0));
+        let calls = hook_calls.clone();
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            session_dry_run: true,
+            before_send_session: Some(Arc::new(move |session_update| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Some(session_update)
+            })),
+            ..Default::default()
+        }));
+
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        hub.start_session();
+        hub.start_session();
+        hub.end_session();
+        hub.start_session();
+        let err = "NaN".parse::<usize>().unwrap_err();
+        hub.capture_error(&err);
+        drop(hub);
+        assert!(!client.flush_sessions());
+
+        // the event is sent as usual, but without any session update
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert!(envelopes[0]
+            .items()
+            .all(|item| !matches!(item, EnvelopeItem::SessionUpdate(_))));
+        assert!(hook_calls.load(Ordering::SeqCst) >= 3);
+        assert_eq!(client.session_stats().sent, 0);
+    }
+
+    #[test]
+    fn test_before_send_session() {
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::configure_scope(|scope| {
+                    scope.set_user(Some(User {
+                        id: Some("foo-bar".into()),
+                        ..Default::default()
+                    }))
+                });
+                sentry::start_session();
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                before_send_session: Some(Arc::new(|mut session_update| {
+                    session_update.distinct_id = None;
+                    Some(session_update)
+                })),
+                ..Default::default()
+            },
+        );
+        assert_eq!(envelopes.len(), 1);
+        match envelopes[0].items().next() {
+            Some(EnvelopeItem::SessionUpdate(session)) => assert_eq!(session.distinct_id, None),
+            _ => panic!("expected session"),
+        }
+
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+                let err = "NaN".parse::<usize>().unwrap_err();
+                sentry::capture_error(&err);
+                sentry::end_session();
+                sentry::start_session();
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                before_send_session: Some(Arc::new(|_| None)),
+                ..Default::default()
+            },
+        );
+        // only the error event is sent, without any session
+        assert_eq!(envelopes.len(), 1);
+        let mut items = envelopes[0].items();
+        assert!(matches!(items.next(), Some(EnvelopeItem::Event(_))));
+        assert_eq!(items.next(), None);
+    }
+
+    #[test]
+    fn test_current_session() {
+        capture_envelopes(|| {
+            assert_eq!(sentry::current_session(), None);
+
+            sentry::start_session();
+            let session = sentry::current_session().unwrap();
+            assert_eq!(session.status, SessionStatus::Ok);
+            assert_eq!(session.errors, 0);
+
+            let err = "NaN".parse::<usize>().unwrap_err();
+            sentry::capture_error(&err);
+            let snapshot = sentry::current_session().unwrap();
+            assert_eq!(snapshot.session_id, session.session_id);
+            assert_eq!(snapshot.started, session.started);
+            assert_eq!(snapshot.errors, 1);
+
+            sentry::end_session();
+            assert_eq!(sentry::current_session(), None);
+        });
+    }
+
+    #[test]
+    fn test_session_crash_info() {
+        use crate::protocol::{Exception, Mechanism};
+
+        capture_envelopes(|| {
+            sentry::start_session();
+            let err = "NaN".parse::<usize>().unwrap_err();
+            sentry::capture_error(&err);
+            assert_eq!(sentry::current_session().unwrap().crash_info, None);
+
+            let event = Event {
+                exception: vec![Exception {
+                    ty: "Panic".into(),
+                    mechanism: Some(Mechanism {
+                        handled: Some(false),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }]
+                .into(),
+                ..Default::default()
+            };
+            let event_id = sentry::capture_event(event);
+
+            let snapshot = sentry::current_session().unwrap();
+            assert_eq!(snapshot.status, SessionStatus::Crashed);
+            let crash_info = snapshot.crash_info.unwrap();
+            assert_eq!(crash_info.event_id, event_id);
+            assert_eq!(crash_info.exception_type, "Panic");
+        });
+    }
+
+    #[test]
+    fn test_on_session_crash() {
+        use crate::protocol::{Exception, Mechanism};
+
+        let crashes = Arc::new(Mutex::new(vec![]));
+        let on_session_crash = {
+            let crashes = crashes.clone();
+            Arc::new(move |session_update: &SessionUpdate<'static>| {
+                crashes.lock().unwrap().push(session_update.clone());
+            })
+        };
+        let mut session_id = None;
+        crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+                session_id = Some(sentry::current_session().unwrap().session_id);
+                let err = "NaN".parse::<usize>().unwrap_err();
+                sentry::capture_error(&err);
+
+                let unhandled = || Event {
+                    exception: vec![Exception {
+                        ty: "Panic".into(),
+                        mechanism: Some(Mechanism {
+                            handled: Some(false),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }]
+                    .into(),
+                    ..Default::default()
+                };
+                sentry::capture_event(unhandled());
+                // the crashed session does not transition again
+                sentry::capture_event(unhandled());
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                on_session_crash: Some(on_session_crash),
+                ..Default::default()
+            },
+        );
+
+        let crashes = crashes.lock().unwrap();
+        assert_eq!(crashes.len(), 1);
+        assert_eq!(Some(crashes[0].session_id), session_id);
+        assert_eq!(crashes[0].status, SessionStatus::Crashed);
+        assert_eq!(crashes[0].errors, 2);
+    }
+
+    #[test]
+    fn test_session_fatal_is_crash() {
+        let run = |session_fatal_is_crash| {
+            let mut result = None;
+            crate::test::with_captured_envelopes_options(
+                || {
+                    sentry::start_session();
+                    sentry::capture_message("fatal", Level::Fatal);
+                    let snapshot = sentry::current_session().unwrap();
+                    let crashed = snapshot.crash_info.is_some();
+                    result = Some((snapshot.status, snapshot.errors, crashed));
+                },
+                crate::ClientOptions {
+                    release: Some("some-release".into()),
+                    session_fatal_is_crash,
+                    ..Default::default()
+                },
+            );
+            result.unwrap()
+        };
+
+        // fatal events are only counted as errors by default
+        assert_eq!(run(false), (SessionStatus::Ok, 1, false));
+        assert_eq!(run(true), (SessionStatus::Crashed, 1, true));
+    }
+
+    #[test]
+    fn test_session_error_level_threshold() {
+        let errors = |session_error_level_threshold, level| {
+            let mut errors = None;
+            crate::test::with_captured_envelopes_options(
+                || {
+                    sentry::start_session();
+                    sentry::capture_message("message", level);
+                    errors = Some(sentry::current_session().unwrap().errors);
+                },
+                crate::ClientOptions {
+                    release: Some("some-release".into()),
+                    session_error_level_threshold,
+                    ..Default::default()
+                },
+            );
+            errors.unwrap()
+        };
+
+        assert_eq!(errors(Level::Error, Level::Warning), 0);
+        assert_eq!(errors(Level::Error, Level::Error), 1);
+        assert_eq!(errors(Level::Warning, Level::Warning), 1);
+        assert_eq!(errors(Level::Warning, Level::Info), 0);
+        assert_eq!(errors(Level::Fatal, Level::Error), 0);
+        assert_eq!(errors(Level::Fatal, Level::Fatal), 1);
+    }
+
+    #[test]
+    fn test_capture_event_with_session() {
+        let error = || Event {
+            level: Level::Error,
+            ..Default::default()
+        };
+        crate::test::with_captured_envelopes_options(
+            || {
+                let hub = sentry::Hub::current();
+                // without a session
+                let captured = hub.capture_event_with_session(error());
+                assert!(!captured.event_id.is_nil());
+                assert!(!captured.session_affected);
+
+                hub.start_session();
+                assert!(hub.capture_event_with_session(error()).session_affected);
+                let info = Event {
+                    level: Level::Info,
+                    ..Default::default()
+                };
+                assert!(!hub.capture_event_with_session(info).session_affected);
+
+                hub.end_session();
+                assert!(!hub.capture_event_with_session(error()).session_affected);
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_session_ignore_for_session_health() {
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+
+            let err = "NaN".parse::<usize>().unwrap_err();
+            sentry::with_scope(
+                |scope| scope.set_ignore_for_session_health(true),
+                || sentry::capture_error(&err),
+            );
+            assert_eq!(sentry::current_session().unwrap().errors, 0);
+
+            // the flag only applies within its scope
+            sentry::capture_error(&err);
+            assert_eq!(sentry::current_session().unwrap().errors, 1);
+        });
+
+        let events = envelopes
+            .iter()
+            .filter(|envelope| envelope.event().is_some())
+            .count();
+        assert_eq!(events, 2);
+    }
+
+    #[test]
+    fn test_session_batching() {
+        #![allow(clippy::match_like_matches_macro)]
+        let envelopes = capture_envelopes(|| {
+            for _ in 0..(MAX_SESSION_ITEMS * 2) {
+                sentry::start_session();
+            }
+        });
+        // we only want *two* envelope for all the sessions
+        assert_eq!(envelopes.len(), 2);
+
+        let items = envelopes[0].items().chain(envelopes[1].items());
+        assert_eq!(items.clone().count(), MAX_SESSION_ITEMS * 2);
+        for item in items {
+            assert!(match item {
+                EnvelopeItem::SessionUpdate(_) => true,
+                _ => false,
+            });
+        }
+    }
+
+    #[test]
+    fn test_session_batching_custom_size() {
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                for _ in 0..20 {
+                    sentry::start_session();
+                }
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                max_session_items: 10,
+                ..Default::default()
+            },
+        );
+        assert_eq!(envelopes.len(), 2);
+        assert_eq!(envelopes[0].items().count(), 10);
+        assert_eq!(envelopes[1].items().count(), 10);
+    }
+
+    #[test]
+    fn test_session_batching_byte_budget() {
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::configure_scope(|scope| {
+                    scope.set_user(Some(sentry::User {
+                        id: Some("x".repeat(1000)),
+                        ..Default::default()
+                    }))
+                });
+                for _ in 0..10 {
+                    sentry::start_session();
+                }
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                max_session_envelope_bytes: 3000,
+                ..Default::default()
+            },
+        );
+        // every update is estimated at more than 1000 bytes, so only two of
+        // them fit into a single envelope
+        let items: Vec<_> = envelopes.iter().map(|e| e.items().count()).collect();
+        assert_eq!(items, vec![2, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_is_session_enabled() {
+        let client = |options: crate::ClientOptions| Client::from(options).is_session_enabled();
+
+        assert!(!client(Default::default()));
+        assert!(client(crate::ClientOptions {
+            release: Some("some-release".into()),
+            ..Default::default()
+        }));
+        assert!(client(crate::ClientOptions {
+            require_release_for_sessions: false,
+            ..Default::default()
+        }));
+        assert!(!client(crate::ClientOptions {
+            release: Some("some-release".into()),
+            session_mode: crate::SessionMode::Disabled,
+            ..Default::default()
+        }));
+    }
+
+    #[test]
+    fn test_session_transport() {
+        let transport = crate::test::TestTransport::new();
+        let session_transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            session_transport: Some(Arc::new(session_transport.clone())),
+            ..Default::default()
+        };
+        let hub = sentry::Hub::new(Some(Arc::new(options.into())), Default::default());
+        hub.start_session();
+        let err = "NaN".parse::<usize>().unwrap_err();
+        hub.capture_error(&err);
+        hub.end_session();
+        hub.start_session();
+        drop(hub);
+
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        let mut items = envelopes[0].items();
+        assert!(matches!(items.next(), Some(EnvelopeItem::Event(_))));
+        assert_eq!(items.next(), None);
+
+        let session_envelopes = session_transport.fetch_and_clear_envelopes();
+        assert_eq!(session_envelopes.len(), 3);
+        for envelope in &session_envelopes {
+            for item in envelope.items() {
+                assert!(matches!(item, EnvelopeItem::SessionUpdate(_)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_session_flusher_thread_options() {
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                session_flusher_stack_size: Some(64 * 1024),
+                session_flusher_thread_prefix: Some("my-client".into()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(envelopes.len(), 1);
+    }
+
+    #[test]
+    fn test_session_flush_scheduler() {
+        type Tick = Box<dyn FnMut() + Send>;
+        let ticks: Arc<Mutex<Vec<Tick>>> = Default::default();
+        let scheduler_ticks = ticks.clone();
+        let (client, transport) = {
+            let transport = crate::test::TestTransport::new();
+            let options = crate::ClientOptions {
+                dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+                release: Some("some-release".into()),
+                transport: Some(Arc::new(transport.clone())),
+                session_flush_scheduler: Some(Arc::new(move |interval, tick| {
+                    assert_eq!(interval, Duration::from_secs(60));
+                    scheduler_ticks.lock().unwrap().push(tick);
+                })),
+                ..Default::default()
+            };
+            (Arc::new(Client::from(options)), transport)
+        };
+        assert_eq!(ticks.lock().unwrap().len(), 1);
+
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        hub.start_session();
+        hub.start_session();
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+
+        // manually tick the fake scheduler
+        (ticks.lock().unwrap()[0])();
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+
+        drop(hub);
+        drop(client);
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+        // ticking after the client is gone does nothing
+        (ticks.lock().unwrap()[0])();
+    }
+
+    #[test]
+    fn test_session_flush_debounce() {
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            session_flush_debounce: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let hub = Arc::new(sentry::Hub::new(
+            Some(Arc::new(options.into())),
+            Default::default(),
+        ));
+
+        // the flusher thread is already waiting for its regular interval
+        std::thread::sleep(Duration::from_millis(50));
+        sentry::Hub::run(hub.clone(), || {
+            sentry::start_session();
+            sentry::start_session();
+            sentry::start_session();
+        });
+
+        // the lone session is flushed well before the 60s interval
+        let mut envelopes = vec![];
+        for _ in 0..100 {
+            envelopes = transport.fetch_and_clear_envelopes();
+            if !envelopes.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 2);
+    }
+
+    struct PanickingTransport {
+        panicked: AtomicBool,
+        inner: Arc<crate::test::TestTransport>,
+    }
+
+    impl crate::Transport for PanickingTransport {
+        fn send_envelope(&self, envelope: Envelope) {
+            if !self.panicked.swap(true, Ordering::SeqCst) {
+                panic!("transport failure");
+            }
+            self.inner.send_envelope(envelope)
+        }
+    }
+
+    #[test]
+    fn test_session_flusher_panic() {
+        let transport = crate::test::TestTransport::new();
+        let panicking = Arc::new(PanickingTransport {
+            panicked: AtomicBool::new(false),
+            inner: transport.clone(),
+        });
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(panicking.clone())),
+            session_flush_debounce: Some(Duration::from_millis(10)),
+            ..Default::default()
+        }));
+        let wait_for = |condition: &dyn Fn() -> bool| {
+            let started = Instant::now();
+            while !condition() && started.elapsed() < Duration::from_secs(5) {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        };
+
+        // the first flush of the background thread panics
+        let session = SessionUpdate::builder("some-release").build();
+        client.enqueue_session(session).unwrap();
+        wait_for(&|| panicking.panicked.load(Ordering::SeqCst));
+        assert!(panicking.panicked.load(Ordering::SeqCst));
+
+        // but the thread keeps flushing later updates
+        let session = SessionUpdate::builder("some-release").build();
+        let session_id = session.session_id;
+        client.enqueue_session(session).unwrap();
+        let envelopes = std::cell::RefCell::new(vec![]);
+        wait_for(&|| {
+            let mut envelopes = envelopes.borrow_mut();
+            envelopes.extend(transport.fetch_and_clear_envelopes());
+            !envelopes.is_empty()
+        });
+        let envelopes = envelopes.into_inner();
+        assert_eq!(envelopes.len(), 1);
+        match envelopes[0].items().next() {
+            Some(EnvelopeItem::SessionUpdate(session)) => {
+                assert_eq!(session.session_id, session_id)
+            }
+            _ => panic!("expected session"),
+        }
+    }
+
+    #[test]
+    fn test_session_flusher_revives_worker() {
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        };
+        let transport: Arc<dyn crate::Transport> = transport;
+        let flusher = SessionFlusher::builder(Arc::new(std::sync::RwLock::new(Some(transport))))
+            .options(&options)
+            .build();
+
+        // replace the worker with one whose thread has already exited
+        let (done_sender, done) = channel();
+        drop(done_sender);
+        let dead = FlusherWorker {
+            shutdown: Arc::new((Mutex::new(false), Condvar::new())),
+            handle: std::thread::spawn(|| {}),
+            done,
+        };
+        let previous = locked(&flusher.worker).replace(dead).unwrap();
+        previous.signal_shutdown();
+        previous.handle.join().unwrap();
+        assert!(!locked(&flusher.worker).as_ref().unwrap().is_alive());
+
+        flusher.enqueue(SessionUpdate::builder("some-release").build());
+        assert!(locked(&flusher.worker).as_ref().unwrap().is_alive());
+    }
+
+    #[test]
+    fn test_session_synchronous_flush() {
+        let transport = crate::test::TestTransport::new();
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            max_session_items: 2,
+            synchronous_session_flush: true,
+            ..Default::default()
+        }));
+
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        // the first update is only queued
+        hub.start_session();
+        hub.start_session();
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+        // the second one fills the queue, which is flushed on this thread
+        hub.start_session();
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 2);
+
+        hub.start_session();
+        assert!(client.flush_sessions());
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+
+        // the final flush happens when the client is closed
+        drop(hub);
+        assert!(client.close(Some(Duration::from_secs(1))));
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+
+        let flusher = SessionFlusher::builder(Arc::new(std::sync::RwLock::new(None)))
+            .options(&crate::ClientOptions {
+                synchronous_session_flush: true,
+                ..Default::default()
+            })
+            .build();
+        assert!(flusher.worker.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_session_flusher_without_thread() {
+        let transport = crate::test::TestTransport::new();
+        let flusher_transport: Arc<dyn crate::Transport> = transport.clone();
+        let flusher =
+            SessionFlusher::builder(Arc::new(std::sync::RwLock::new(Some(flusher_transport))))
+                .options(&crate::ClientOptions {
+                    max_session_items: 2,
+                    ..Default::default()
+                })
+                // this is what spawning a thread reports when out of resources
+                .spawn_thread(|_, _| Err(io::ErrorKind::WouldBlock.into()))
+                .build();
+        assert!(flusher.worker.lock().unwrap().is_none());
+
+        // the flusher falls back to flushing synchronously
+        flusher.enqueue(SessionUpdate::builder("some-release").build());
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+        flusher.enqueue(SessionUpdate::builder("some-release").build());
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+
+        flusher.enqueue(SessionUpdate::builder("some-release").build());
+        drop(flusher);
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "session-signals"))]
+    fn test_session_pending_signal() {
+        let transport = crate::test::TestTransport::new();
+        let flusher_transport: Arc<dyn crate::Transport> = transport.clone();
+        let flusher_transport = Arc::new(std::sync::RwLock::new(Some(flusher_transport)));
+        let event_transport: Arc<dyn crate::Transport> = crate::test::TestTransport::new();
+        let event_transport = Arc::new(std::sync::RwLock::new(Some(event_transport)));
+        let flusher = SessionFlusher::builder(flusher_transport.clone())
+            .event_transport(event_transport.clone())
+            .build();
+        flusher.enqueue(SessionUpdate::builder("some-release").build());
+
+        let state = flusher.state();
+        assert_eq!(state.handle_pending_signal(), None);
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+        assert!(flusher_transport.read().unwrap().is_some());
+
+        // this is what the signal handler does
+        state
+            .signals
+            .pending
+            .store(signal_hook::SIGTERM as usize, Ordering::SeqCst);
+        assert_eq!(state.handle_pending_signal(), Some(signal_hook::SIGTERM));
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+        // both transports were shut down before the signal is re-raised
+        assert!(flusher_transport.read().unwrap().is_none());
+        assert!(event_transport.read().unwrap().is_none());
+        // the signal is only handled once
+        assert_eq!(state.handle_pending_signal(), None);
+    }
+
+    #[test]
+    #[cfg(all(unix, feature = "session-signals"))]
+    fn test_session_signal_handler_installed() {
+        let transport: Arc<dyn crate::Transport> = crate::test::TestTransport::new();
+        let transport = Arc::new(std::sync::RwLock::new(Some(transport)));
+        let build = |synchronous_session_flush| {
+            SessionFlusher::builder(transport.clone())
+                .options(&crate::ClientOptions {
+                    install_session_signal_handler: true,
+                    synchronous_session_flush,
+                    ..Default::default()
+                })
+                .build()
+        };
+
+        // nothing would handle the signals of a synchronous flusher
+        assert!(!build(true).state().signals.is_installed());
+
+        let flusher = build(false);
+        assert!(flusher.state().signals.is_installed());
+        assert!(flusher.shutdown(Duration::from_secs(1)));
+        assert!(!flusher.state().signals.is_installed());
+    }
+
+    #[test]
+    fn test_session_flush_interval() {
+        type Tick = Box<dyn FnMut() + Send>;
+        let ticks: Arc<Mutex<Vec<Tick>>> = Default::default();
+        let scheduler_ticks = ticks.clone();
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            // this is clamped to the minimum of one second
+            session_flush_interval: Duration::from_secs(0),
+            session_flush_scheduler: Some(Arc::new(move |interval, tick| {
+                assert_eq!(interval, Duration::from_secs(1));
+                scheduler_ticks.lock().unwrap().push(tick);
+            })),
+            ..Default::default()
+        };
+        let hub = Arc::new(sentry::Hub::new(
+            Some(Arc::new(options.into())),
+            Default::default(),
+        ));
+
+        sentry::Hub::run(hub.clone(), || {
+            sentry::start_session();
+            // starting a second session drops and enqueues the first one
+            sentry::start_session();
+        });
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+
+        // tick once the interval has elapsed
+        (ticks.lock().unwrap()[0])();
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+
+        let mut items = envelopes[0].items();
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Exited);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
+    #[test]
+    fn test_session_reinit_after_fork() {
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            session_flush_interval: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let client = Arc::new(Client::from(options));
+
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        hub.start_session();
+        hub.start_session();
+
+        // the update queued by the "parent" is discarded
+        client.reinit_after_fork();
+        assert!(!client.flush_sessions());
+
+        // and the new background thread does the final flush
+        drop(hub);
+        assert!(client.close(Some(Duration::from_secs(1))));
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 1);
+    }
+
+    #[test]
+    fn test_session_reinit_after_fork_locked() {
+        let transport = crate::test::TestTransport::new();
+        let flusher_transport: Arc<dyn crate::Transport> = transport.clone();
+        let flusher =
+            SessionFlusher::builder(Arc::new(std::sync::RwLock::new(Some(flusher_transport))))
+                .build();
+
+        // a thread of the parent held the lock of the open sessions when
+        // forking, which is never released in the child
+        let previous = flusher.state();
+        mem::forget(locked(&previous.open_sessions));
+
+        flusher.reinit_after_fork();
+        assert!(!Arc::ptr_eq(&previous, &flusher.state()));
+        assert!(flusher.worker.lock().unwrap().as_ref().unwrap().is_alive());
+
+        // the replaced state is used from now on
+        flusher.enqueue(SessionUpdate::builder("some-release").build());
+        assert!(flusher.shutdown(Duration::from_secs(1)));
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 1);
+    }
+
+    #[test]
+    fn test_session_flush_on_drop() {
+        let flush_on_drop = |session_flush_on_drop| {
+            let transport = crate::test::TestTransport::new();
+            let flusher_transport: Arc<dyn crate::Transport> = transport.clone();
+            let flusher =
+                SessionFlusher::builder(Arc::new(std::sync::RwLock::new(Some(flusher_transport))))
+                    .options(&crate::ClientOptions {
+                        session_flush_on_drop,
+                        ..Default::default()
+                    })
+                    .build();
+            let session_update = SessionUpdate::builder("some-release").build();
+            flusher.track(&session_update, Instant::now());
+            flusher.enqueue(session_update);
+            drop(flusher);
+            transport.fetch_and_clear_envelopes().len()
+        };
+
+        // the queued update, and the open session as `Abnormal`
+        assert_eq!(flush_on_drop(true), 2);
+        assert_eq!(flush_on_drop(false), 0);
+    }
+
+    #[test]
+    fn test_session_flush_on_drop_after_fork() {
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        };
+        let client = Client::from(options.clone());
+
+        // a forked child does not flush on drop
+        client.reinit_after_fork();
+        let session_update = SessionUpdate::builder("some-release").build();
+        client.enqueue_session(session_update).unwrap();
+        drop(client);
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+
+        // unless it opts in again
+        let client = Client::from(options);
+        client.reinit_after_fork();
+        client.set_session_flush_on_drop(true);
+        let session_update = SessionUpdate::builder("some-release").build();
+        client.enqueue_session(session_update).unwrap();
+        drop(client);
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+    }
+
+    #[test]
+    fn test_session_flush_now() {
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        };
+        let client = Arc::new(Client::from(options));
+        let hub = Arc::new(sentry::Hub::new(Some(client.clone()), Default::default()));
+
+        assert!(!client.flush_sessions());
+
+        sentry::Hub::run(hub, || {
+            sentry::start_session();
+            sentry::start_session();
+        });
+
+        assert!(client.flush_sessions());
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+        assert!(!client.flush_sessions());
+    }
+
+    #[test]
+    fn test_session_stats() {
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        };
+        let client = Arc::new(Client::from(options));
+
+        let hub = Arc::new(sentry::Hub::new(Some(client.clone()), Default::default()));
+        sentry::Hub::run(hub, || {
+            sentry::start_session();
+            sentry::start_session();
+        });
+        assert!(client.flush_sessions());
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+
+        let stats = client.session_stats();
+        assert_eq!(stats.sent, 2);
+        assert_eq!(stats.dropped_no_transport, 0);
+
+        // the transport is gone after closing the client
+        client.close(None);
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        hub.start_session();
+        drop(hub);
+        assert!(!client.flush_sessions());
+
+        let stats = client.session_stats();
+        assert_eq!(stats.sent, 2);
+        assert_eq!(stats.dropped_no_transport, 1);
+    }
+
+    #[test]
+    fn test_session_flusher_shutdown() {
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        };
+        let client = Arc::new(Client::from(options));
+
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        hub.start_session();
+        drop(hub);
+
+        // the queued session is flushed before the transport goes away
+        assert!(client.close(Some(Duration::from_secs(1))));
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert!(matches!(
+            envelopes[0].items().next(),
+            Some(EnvelopeItem::SessionUpdate(_))
+        ));
+        assert_eq!(client.session_stats().sent, 1);
+    }
+
+    #[test]
+    fn test_session_flush_metrics() {
+        let transport = crate::test::TestTransport::new();
+        let client = Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        });
+        assert!(client.recent_session_flush_metrics().is_empty());
+
+        let hub = sentry::Hub::new(Some(Arc::new(client)), Default::default());
+        hub.start_session();
+        hub.start_session();
+        hub.start_session();
+        let client = hub.client().unwrap();
+        assert!(client.flush_sessions());
+
+        let metrics = client.recent_session_flush_metrics();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].items, 2);
+        assert!(metrics[0].bytes > 0);
+        assert!(metrics[0].duration > Duration::from_secs(0));
+
+        // only the most recent flushes are kept
+        for _ in 0..RECENT_FLUSH_METRICS {
+            hub.start_session();
+            client.flush_sessions();
+        }
+        let metrics = client.recent_session_flush_metrics();
+        assert_eq!(metrics.len(), RECENT_FLUSH_METRICS);
+        assert!(metrics.iter().all(|metrics| metrics.items == 1));
+    }
+
+    #[test]
+    fn test_capture_sessions_batched() {
+        let transport = crate::test::TestTransport::new();
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        }));
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+
+        let updates = |n| {
+            (0..n)
+                .map(|_| {
+                    SessionUpdate::builder("some-release")
+                        .status(SessionStatus::Exited)
+                        .build()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // the updates are queued in one go, and flushed only once when the
+        // queue is full, instead of once for every `max_session_items`
+        hub.capture_sessions(updates(MAX_SESSION_ITEMS * 2 + 50));
+        assert_eq!(client.recent_session_flush_metrics().len(), 1);
+        assert_eq!(client.pending_session_updates(), 0);
+        let envelopes = transport.fetch_and_clear_envelopes();
+        let items: Vec<_> = envelopes.iter().map(|env| env.items().count()).collect();
+        assert_eq!(items, [MAX_SESSION_ITEMS, MAX_SESSION_ITEMS, 50]);
+
+        // a small batch stays queued until the next flush
+        hub.capture_sessions(updates(10));
+        assert_eq!(client.pending_session_updates(), 10);
+        assert!(client.flush_sessions());
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 10);
+    }
+
+    #[test]
+    fn test_session_flush_on_pressure() {
+        let transport = crate::test::TestTransport::new();
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        }));
+        assert!(!client.flush_sessions_on_pressure());
+
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        for _ in 0..4 {
+            hub.start_session();
+        }
+        assert_eq!(client.pending_session_updates(), 3);
+
+        assert!(client.flush_sessions_on_pressure());
+        assert_eq!(client.pending_session_updates(), 0);
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 3);
+        assert_eq!(client.session_stats().dropped_backpressure, 0);
+    }
+
+    #[test]
+    fn test_session_flush_on_pressure_rejected() {
+        // a transport that rejects all updates, and enqueues another one while sending
+        struct EnqueueingTransport(Mutex<std::sync::Weak<Client>>);
+
+        impl crate::Transport for EnqueueingTransport {
+            fn send_envelope(&self, _envelope: Envelope) {}
+
+            fn try_send_envelope(&self, envelope: Envelope) -> Result<(), Envelope> {
+                if let Some(client) = self.0.lock().unwrap().upgrade() {
+                    let session_update = SessionUpdate::builder("some-release").build();
+                    client.enqueue_session(session_update).unwrap();
+                }
+                Err(envelope)
+            }
+        }
+
+        let transport = Arc::new(EnqueueingTransport(Mutex::new(std::sync::Weak::new())));
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        }));
+        *transport.0.lock().unwrap() = Arc::downgrade(&client);
+        for _ in 0..2 {
+            let session_update = SessionUpdate::builder("some-release").build();
+            client.enqueue_session(session_update).unwrap();
+        }
+
+        // only the rejected updates are dropped
+        assert!(!client.flush_sessions_on_pressure());
+        assert_eq!(client.session_stats().dropped_backpressure, 2);
+        assert_eq!(client.pending_session_updates(), 1);
+    }
+
+    #[test]
+    fn test_session_pending_len() {
+        let transport = crate::test::TestTransport::new();
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        }));
+        assert_eq!(client.pending_session_updates(), 0);
+
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        for _ in 0..4 {
+            hub.start_session();
+        }
+        assert_eq!(client.pending_session_updates(), 3);
+
+        client.flush_sessions();
+        assert_eq!(client.pending_session_updates(), 0);
+    }
+
+    #[test]
+    fn test_session_coalesce_updates() {
+        let update = |session_id, init, errors, status| SessionUpdate {
+            session_id,
+            distinct_id: None,
+            sequence: None,
+            timestamp: None,
+            started: Utc::now(),
+            init,
+            duration: None,
+            status,
+            errors,
+            attributes: SessionAttributes::new("some-release"),
+        };
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let coalesced = coalesce_updates(vec![
+            update(first, true, 0, SessionStatus::Ok),
+            update(first, false, 1, SessionStatus::Ok),
+            update(first, false, 2, SessionStatus::Exited),
+            update(second, true, 0, SessionStatus::Exited),
+            update(first, false, 3, SessionStatus::Exited),
+        ]);
+
+        assert_eq!(coalesced.len(), 3);
+        assert_eq!(coalesced[0].session_id, first);
+        assert!(coalesced[0].init);
+        assert_eq!(coalesced[0].errors, 2);
+        assert_eq!(coalesced[0].status, SessionStatus::Exited);
+        assert_eq!(coalesced[1].session_id, second);
+        assert!(coalesced[1].init);
+        // only consecutive updates are coalesced
+        assert_eq!(coalesced[2].session_id, first);
+        assert!(!coalesced[2].init);
+        assert_eq!(coalesced[2].errors, 3);
+    }
+
+    #[test]
+    fn test_session_duration_monotonic() {
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            release: Some("some-release".into()),
+            ..Default::default()
+        }));
+        let stack = StackLayer {
+            client: Some(client),
+            scope: Default::default(),
+            deferred_session: false,
+            backgrounded: None,
+        };
+        let mut session = Session::from_stack(&stack).unwrap();
+
+        // an hour passed on the monotonic clock, while the wall clock jumped
+        // ahead by a day in the meantime
+        let hour = Duration::from_secs(60 * 60);
+        session.started = Instant::now().checked_sub(hour).unwrap();
+        let tomorrow = Utc::now().timestamp() + 24 * 60 * 60;
+        session.session_update.started = Utc.timestamp_opt(tomorrow, 0).unwrap();
+        session.close();
+
+        let update = &session.session_update;
+        let duration = update.duration.unwrap();
+        assert!(duration >= hour.as_secs_f64());
+        let timestamp = update.timestamp.unwrap();
+        assert!(timestamp >= update.started);
+        let wall_duration = (timestamp - update.started).to_std().unwrap();
+        assert!((wall_duration.as_secs_f64() - duration).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_session_failed_transaction() {
+        use crate::protocol::TraceContext;
+
+        let transaction = |status: &str| {
+            let mut transaction = Transaction::new();
+            let trace = TraceContext {
+                status: Some(status.into()),
+                ..Default::default()
+            };
+            transaction.contexts.insert("trace".into(), trace.into());
+            transaction.finish();
+            transaction
+        };
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+            sentry::Hub::with_active(|hub| {
+                hub.capture_transaction(transaction("ok"));
+                hub.capture_transaction(transaction("internal_error"));
+            });
+        });
+        assert_eq!(envelopes.len(), 3);
+
+        let mut items = envelopes[0].items();
+        assert!(matches!(items.next(), Some(EnvelopeItem::Transaction(_))));
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Ok);
+            assert_eq!(session.errors, 0);
+        } else {
+            panic!("expected session");
+        }
+
+        let mut items = envelopes[1].items();
+        assert!(matches!(items.next(), Some(EnvelopeItem::Transaction(_))));
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Ok);
+            assert_eq!(session.errors, 1);
+        } else {
+            panic!("expected session");
+        }
+
+        let mut items = envelopes[2].items();
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Exited);
+            assert_eq!(session.errors, 1);
+        } else {
+            panic!("expected session");
+        }
+    }
+
+    #[test]
+    fn test_capture_session() {
+        let existing = Uuid::new_v4();
+        let envelopes = capture_envelopes(|| {
+            let session = SessionUpdate::builder("some-release")
+                .distinct_id("foo-bar")
+                .status(SessionStatus::Crashed)
+                .errors(1)
+                .duration(1.5)
+                .build();
+            sentry::capture_session(session);
+
+            let session = SessionUpdate::builder("some-release")
+                .session_id(existing)
+                .status(SessionStatus::Exited)
+                .build();
+            sentry::capture_session(session);
+        });
+        assert_eq!(envelopes.len(), 1);
+
+        let mut items = envelopes[0].items();
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Crashed);
+            assert_eq!(session.distinct_id.as_deref(), Some("foo-bar"));
+            assert_eq!(session.errors, 1);
+            assert_eq!(session.duration, Some(1.5));
+            assert_eq!(session.attributes.release, "some-release");
+            assert!(session.init);
+        } else {
+            panic!("expected session");
+        }
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.session_id, existing);
+            assert_eq!(session.status, SessionStatus::Exited);
+            assert!(!session.init);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
+    #[test]
+    fn test_session_flush_jitter() {
+        let interval = Duration::from_secs(60);
+        assert_eq!(jittered_interval(interval, 0.0), interval);
+
+        let min = Duration::from_secs(45);
+        let max = Duration::from_secs(75);
+        for _ in 0..100 {
+            let jittered = jittered_interval(interval, 0.25);
+            assert!(jittered >= min && jittered <= max);
+        }
+
+        // the jittered interval never drops below the minimum
+        for _ in 0..100 {
+            assert!(jittered_interval(MIN_FLUSH_INTERVAL, 1.0) >= MIN_FLUSH_INTERVAL);
+            assert!(jittered_interval(interval, 5.0) <= interval * 2);
+        }
+        assert_eq!(jittered_interval(interval, -1.0), interval);
+    }
+
+    #[test]
+    fn test_session_abandoned() {
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        };
+        let client = Arc::new(Client::from(options));
+        let stack = StackLayer {
+            client: Some(client.clone()),
+            scope: Default::default(),
+            deferred_session: false,
+            backgrounded: None,
+        };
+
+        // a closed session is not reported again
+        drop(Session::from_stack(&stack));
+        client.flush_sessions();
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+
+        // the session is leaked, and never closed
+        std::mem::forget(Session::from_stack(&stack));
+        assert!(client.close(Some(Duration::from_secs(1))));
+
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        let mut items = envelopes[0].items();
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Abnormal);
+            assert!(session.duration.is_some());
+            assert!(session.init);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
+    /// A transport that rejects the first `failures` envelopes it is given.
+    struct RejectingTransport {
+        failures: AtomicUsize,
+        inner: Arc<crate::test::TestTransport>,
+    }
+
+    impl crate::Transport for RejectingTransport {
+        fn send_envelope(&self, envelope: Envelope) {
+            self.inner.send_envelope(envelope)
+        }
+
+        fn try_send_envelope(&self, envelope: Envelope) -> Result<(), Envelope> {
+            if self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                return Err(envelope);
+            }
+            self.inner.send_envelope(envelope);
+            Ok(())
+        }
+    }
+
+    fn rejecting_client(
+        failures: usize,
+        max_session_items: usize,
+    ) -> (Arc<Client>, Arc<crate::test::TestTransport>) {
+        let inner = crate::test::TestTransport::new();
+        let transport = RejectingTransport {
+            failures: AtomicUsize::new(failures),
+            inner: inner.clone(),
+        };
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(Arc::new(transport))),
+            max_session_items,
+            ..Default::default()
+        };
+        (Arc::new(Client::from(options)), inner)
+    }
+
+    #[test]
+    fn test_session_backpressure() {
+        let (client, transport) = rejecting_client(1, 100);
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        hub.start_session();
+        drop(hub);
+
+        // the first flush is rejected, and the update is retried on the next one
+        assert!(!client.flush_sessions());
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+        assert!(client.flush_sessions());
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+
+        let stats = client.session_stats();
+        assert_eq!(stats.sent, 1);
+        assert_eq!(stats.dropped_backpressure, 0);
+    }
+
+    #[test]
+    fn test_before_send_session_requeued() {
+        let inner = crate::test::TestTransport::new();
+        let transport: Arc<dyn crate::Transport> = Arc::new(RejectingTransport {
+            failures: AtomicUsize::new(1),
+            inner: inner.clone(),
+        });
+        let hook_calls = Arc::new(AtomicUsize::new(0));
+        let calls = hook_calls.clone();
+        let options = crate::ClientOptions {
+            synchronous_session_flush: true,
+            before_send_session: Some(Arc::new(move |mut session_update| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                session_update.distinct_id = None;
+                Some(session_update)
+            })),
+            ..Default::default()
+        };
+        let flusher = SessionFlusher::builder(Arc::new(std::sync::RwLock::new(Some(transport))))
+            .options(&options)
+            .build();
+
+        flusher.enqueue(
+            SessionUpdate::builder("some-release")
+                .distinct_id("foo-bar")
+                .build(),
+        );
+        // the callback runs when flushing, and again for the rejected update
+        assert!(!flusher.flush_now());
+        assert_eq!(hook_calls.load(Ordering::SeqCst), 1);
+        assert!(flusher.flush_now());
+        assert_eq!(hook_calls.load(Ordering::SeqCst), 2);
+
+        let envelopes = inner.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        match envelopes[0].items().next() {
+            Some(EnvelopeItem::SessionUpdate(session)) => assert_eq!(session.distinct_id, None),
+            _ => panic!("expected session"),
+        }
+    }
+
+    #[test]
+    fn test_session_flusher_builder() {
+        let transport = crate::test::TestTransport::new();
+        let flusher_transport: Arc<dyn crate::Transport> = transport.clone();
+        let options = crate::ClientOptions {
+            synchronous_session_flush: true,
+            ..Default::default()
+        };
+        let flusher =
+            SessionFlusher::builder(Arc::new(std::sync::RwLock::new(Some(flusher_transport))))
+                .flush_interval(Duration::from_secs(10))
+                .max_items(2)
+                // the options do not replace the settings overridden before
+                .options(&options)
+                .flush_jitter(0.5)
+                .max_queued(5, SessionDropPolicy::DropNewest)
+                .build();
+
+        let state = flusher.state();
+        assert_eq!(state.flush_interval, Duration::from_secs(10));
+        assert_eq!(state.flush_jitter, 0.5);
+        assert_eq!(state.max_items, 2);
+        assert_eq!(state.max_queued, Some(5));
+        assert_eq!(state.drop_policy, SessionDropPolicy::DropNewest);
+        // the settings that were not overridden are taken from the options
+        assert!(flusher.worker.lock().unwrap().is_none());
+
+        flusher.enqueue(SessionUpdate::builder("some-release").build());
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+        flusher.enqueue(SessionUpdate::builder("some-release").build());
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+    }
+
+    #[test]
+    fn test_session_queue_drop_policy() {
+        let queued_releases = |session_queue_drop_policy| {
+            let transport = crate::test::TestTransport::new();
+            let flusher_transport: Arc<dyn crate::Transport> = transport.clone();
+            let flusher =
+                SessionFlusher::builder(Arc::new(std::sync::RwLock::new(Some(flusher_transport))))
+                    .options(&crate::ClientOptions {
+                        synchronous_session_flush: true,
+                        max_queued_sessions: Some(3),
+                        session_queue_drop_policy,
+                        ..Default::default()
+                    })
+                    .build();
+            let accepted: Vec<_> = (0..5)
+                .map(|i| flusher.enqueue(SessionUpdate::builder(format!("release-{}", i)).build()))
+                .collect();
+            assert_eq!(flusher.pending_len(), (3, 0));
+            assert_eq!(flusher.stats().dropped_backpressure, 2);
+
+            assert!(flusher.flush_now());
+            let envelopes = transport.fetch_and_clear_envelopes();
+            let releases: Vec<_> = envelopes[0]
+                .items()
+                .filter_map(|item| match item {
+                    EnvelopeItem::SessionUpdate(session) => {
+                        Some(session.attributes.release.to_string())
+                    }
+                    _ => None,
+                })
+                .collect();
+            (accepted, releases)
+        };
+
+        let (accepted, releases) = queued_releases(SessionDropPolicy::DropOldest);
+        assert_eq!(accepted, [true; 5]);
+        assert_eq!(releases, ["release-2", "release-3", "release-4"]);
+
+        let (accepted, releases) = queued_releases(SessionDropPolicy::DropNewest);
+        assert_eq!(accepted, [true, true, true, false, false]);
+        assert_eq!(releases, ["release-0", "release-1", "release-2"]);
+
+        // manually enqueued updates report the full queue
+        let client = Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(crate::test::TestTransport::new())),
+            synchronous_session_flush: true,
+            max_queued_sessions: Some(1),
+            session_queue_drop_policy: SessionDropPolicy::DropNewest,
+            ..Default::default()
+        });
+        let session = SessionUpdate::builder("some-release").build();
+        assert_eq!(client.enqueue_session(session), Ok(()));
+        let session = SessionUpdate::builder("some-release").build();
+        assert_eq!(
+            client.enqueue_session(session),
+            Err(SessionUpdateError::QueueFull)
+        );
+    }
+
+    #[test]
+    fn test_session_backpressure_drops_oldest() {
+        let (client, transport) = rejecting_client(3, 2);
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        // the queue is flushed once it is full, which is rejected, and then
+        // overflows when the next update is rejected as well
+        hub.start_session();
+        hub.start_session();
+        hub.start_session();
+        drop(hub);
+
+        assert!(client.flush_sessions());
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 2);
+
+        let stats = client.session_stats();
+        assert_eq!(stats.sent, 2);
+        assert_eq!(stats.dropped_backpressure, 1);
+    }
+
+    #[test]
+    fn test_session_flush_retries() {
+        let (client, transport) = rejecting_client(2, 100);
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        hub.start_session();
+        drop(hub);
+
+        // the transport fails twice, and the update is retained until it succeeds
+        assert!(!client.flush_sessions());
+        assert!(!client.flush_sessions());
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+        assert!(client.flush_sessions());
+        assert_eq!(transport.fetch_and_clear_envelopes().len(), 1);
+
+        let stats = client.session_stats();
+        assert_eq!(stats.sent, 1);
+        assert_eq!(stats.dropped_backpressure, 0);
+    }
+
+    #[test]
+    fn test_client_enqueue_session() {
+        let transport = crate::test::TestTransport::new();
+        let client = Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(transport.clone())),
+            ..Default::default()
+        });
+
+        let session = SessionUpdate::builder("some-release")
+            .status(SessionStatus::Exited)
+            .errors(1)
+            .duration(1.5)
+            .build();
+        assert_eq!(client.enqueue_session(session), Ok(()));
+
+        let session = SessionUpdate::builder("").build();
+        assert_eq!(
+            client.enqueue_session(session),
+            Err(SessionUpdateError::EmptyRelease)
+        );
+        let session = SessionUpdate::builder("some-release")
+            .duration(-1.0)
+            .build();
+        assert_eq!(
+            client.enqueue_session(session),
+            Err(SessionUpdateError::InvalidDuration)
+        );
+        let session = SessionUpdate::builder("some-release").errors(1001).build();
+        assert_eq!(
+            client.enqueue_session(session),
+            Err(SessionUpdateError::TooManyErrors)
+        );
+
+        client.flush_sessions();
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 1);
+
+        client.close(Some(Duration::from_secs(1)));
+        let session = SessionUpdate::builder("some-release").build();
+        assert_eq!(
+            client.enqueue_session(session),
+            Err(SessionUpdateError::TransportUnavailable)
+        );
+        assert_eq!(
+            Client::from(crate::ClientOptions::default())
+                .enqueue_session(SessionUpdate::builder("some-release").build()),
+            Err(SessionUpdateError::TransportUnavailable)
+        );
+
+        let client = Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            transport: Some(Arc::new(transport.clone())),
+            session_mode: crate::SessionMode::Disabled,
+            ..Default::default()
+        });
+        let session = SessionUpdate::builder("some-release").build();
+        assert_eq!(
+            client.enqueue_session(session),
+            Err(SessionUpdateError::Disabled)
+        );
+    }
+
+    #[test]
+    fn test_sanitize_duration() {
+        assert_eq!(sanitize_duration(1.5), 1.5);
+        assert_eq!(sanitize_duration(0.0), 0.0);
+        assert_eq!(sanitize_duration(-0.0), 0.0);
+        assert_eq!(sanitize_duration(-1.0), 0.0);
+        assert_eq!(sanitize_duration(f64::NAN), 0.0);
+        assert_eq!(sanitize_duration(f64::INFINITY), 0.0);
+
+        let envelopes = capture_envelopes(|| {
+            let session = SessionUpdate::builder("some-release")
+                .status(SessionStatus::Exited)
+                .duration(f64::NAN)
+                .build();
+            sentry::capture_session(session);
+        });
+        match envelopes[0].items().next() {
+            Some(EnvelopeItem::SessionUpdate(session)) => assert_eq!(session.duration, Some(0.0)),
+            _ => panic!("expected session"),
+        }
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let interval = Duration::from_secs(60);
+        assert_eq!(retry_backoff(1, interval), Duration::from_secs(1));
+        assert_eq!(retry_backoff(2, interval), Duration::from_secs(2));
+        assert_eq!(retry_backoff(3, interval), Duration::from_secs(4));
+        // the backoff never exceeds the regular flush interval
+        assert_eq!(retry_backoff(10, interval), interval);
+        assert_eq!(retry_backoff(usize::MAX, interval), interval);
+    }
+
+    #[test]
+    fn test_session_backpressure_max_attempts() {
+        let max_retries = crate::ClientOptions::default().max_session_flush_retries;
+        let (client, transport) = rejecting_client(max_retries + 1, 100);
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        hub.start_session();
+        drop(hub);
+
+        for _ in 0..=max_retries {
+            assert!(!client.flush_sessions());
+        }
+        // the update was dropped after the last rejected attempt
+        assert!(!client.flush_sessions());
+        assert!(transport.fetch_and_clear_envelopes().is_empty());
+
+        let stats = client.session_stats();
+        assert_eq!(stats.sent, 0);
+        assert_eq!(stats.dropped_backpressure, 1);
+    }
+
+    #[test]
+    fn test_session_spool_replay() {
+        let spool_dir = std::env::temp_dir().join(format!("sentry-spool-{}", Uuid::new_v4()));
+        let spooled_files = || {
+            fs::read_dir(&spool_dir)
+                .unwrap()
+                .filter(|entry| {
+                    let path = entry.as_ref().unwrap().path();
+                    path.extension() == Some(SPOOL_EXTENSION.as_ref())
+                })
+                .count()
+        };
+
+        // the update is spooled when the client is dropped, as the transport rejects it
+        let inner = crate::test::TestTransport::new();
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(Arc::new(RejectingTransport {
+                failures: AtomicUsize::new(usize::MAX),
+                inner: inner.clone(),
+            }))),
+            session_spool_dir: Some(spool_dir.clone()),
+            ..Default::default()
+        }));
+        let hub = sentry::Hub::new(Some(client.clone()), Default::default());
+        hub.start_session();
+        drop(hub);
+        drop(client);
+        assert!(inner.fetch_and_clear_envelopes().is_empty());
+        assert_eq!(spooled_files(), 1);
+
+        // corrupt files are skipped
+        fs::write(
+            spool_dir.join("corrupt").with_extension(SPOOL_EXTENSION),
+            "not json",
+        )
+        .unwrap();
+
+        let transport = crate::test::TestTransport::new();
+        let client = Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            session_spool_dir: Some(spool_dir.clone()),
+            ..Default::default()
+        });
+        assert_eq!(spooled_files(), 0);
+        assert!(client.flush_sessions());
+
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        let mut items = envelopes[0].items();
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Exited);
+            assert_eq!(session.attributes.release, "some-release");
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+
+        // cloning the client does not replay the spool again
+        let session_updates: Vec<_> = (0..3)
+            .map(|_| SessionUpdate::builder("some-release").build())
+            .collect();
+        write_spool_file(&spool_dir, &session_updates).unwrap();
+        drop(client.clone());
+        assert_eq!(spooled_files(), 1);
+
+        // replayed updates count towards the queue limit
+        let client = Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            session_spool_dir: Some(spool_dir.clone()),
+            max_queued_sessions: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(spooled_files(), 0);
+        assert!(client.flush_sessions());
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].items().count(), 2);
+        assert_eq!(client.session_stats().dropped_backpressure, 1);
+
+        // the number of spool files is capped
+        drop(client);
+        let flusher = SessionFlusher::builder(Arc::new(std::sync::RwLock::new(None)))
+            .options(&crate::ClientOptions {
+                session_spool_dir: Some(spool_dir.clone()),
+                ..Default::default()
+            })
+            .build();
+        for _ in 0..MAX_SPOOL_FILES {
+            assert!(flusher.state().spool(&session_updates));
+        }
+        assert!(!flusher.state().spool(&session_updates));
+        assert_eq!(spooled_files(), MAX_SPOOL_FILES);
+
+        drop(flusher);
+        fs::remove_dir_all(&spool_dir).ok();
+    }
+
+    #[test]
+    fn test_session_spool_claimed_once() {
+        let spool_dir = std::env::temp_dir().join(format!("sentry-spool-{}", Uuid::new_v4()));
+        let session_updates = vec![SessionUpdate::builder("some-release").build()];
+        for _ in 0..MAX_SPOOL_FILES {
+            write_spool_file(&spool_dir, &session_updates).unwrap();
+        }
+
+        // clients sharing the directory never replay the same file twice
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let spool_dir = spool_dir.clone();
+                std::thread::spawn(move || take_spooled_updates(&spool_dir, false).len())
+            })
+            .collect();
+        let replayed: usize = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .sum();
+        assert_eq!(replayed, MAX_SPOOL_FILES);
+        assert!(spool_files(&spool_dir).unwrap().is_empty());
+
+        fs::remove_dir_all(&spool_dir).ok();
+    }
+
+    #[test]
+    fn test_session_concurrency() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Session>();
+        assert_send_sync::<SessionFlusher>();
+
+        const THREADS: usize = 8;
+        const SESSIONS: usize = 20;
+        const ERRORS: u64 = 3;
+
+        let transport = crate::test::TestTransport::new();
+        let client = Arc::new(Client::from(crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(transport.clone())),
+            max_session_items: 10,
+            ..Default::default()
+        }));
+
+        let threads: Vec<_> = (0..THREADS)
+            .map(|thread| {
+                let client = client.clone();
+                std::thread::spawn(move || {
+                    let hub = sentry::Hub::new(Some(client), Default::default());
+                    for _ in 0..SESSIONS {
+                        hub.start_session();
+                        for _ in 0..ERRORS {
+                            hub.capture_message("error", Level::Error);
+                        }
+                        // sessions are either closed explicitly, or replaced
+                        // by the next one, which queues their final update
+                        if thread % 2 == 0 {
+                            hub.end_session();
+                        }
+                    }
+                    hub.end_session();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        client.flush_sessions();
+
+        let mut sessions = HashMap::new();
+        for envelope in transport.fetch_and_clear_envelopes() {
+            for item in envelope.items() {
+                if let EnvelopeItem::SessionUpdate(session) = item {
+                    if session.status == SessionStatus::Exited {
+                        assert!(sessions
+                            .insert(session.session_id, session.errors)
+                            .is_none());
+                    }
+                }
+            }
+        }
+        assert_eq!(sessions.len(), THREADS * SESSIONS);
+        assert_eq!(
+            sessions.values().sum::<u64>(),
+            (THREADS * SESSIONS) as u64 * ERRORS
+        );
+        assert_eq!(client.session_stats().dropped_backpressure, 0);
+    }
+
+    fn end_with_status(status: SessionStatus) -> SessionStatus {
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+            sentry::end_session_with_status(status);
+        });
+        assert_eq!(envelopes.len(), 1);
+        match envelopes[0].items().next() {
+            Some(EnvelopeItem::SessionUpdate(session)) => session.status,
+            _ => panic!("expected session"),
+        }
+    }
+
+    #[test]
+    fn test_session_end_with_status() {
+        assert_eq!(
+            end_with_status(SessionStatus::Exited),
+            SessionStatus::Exited
+        );
+        assert_eq!(
+            end_with_status(SessionStatus::Abnormal),
+            SessionStatus::Abnormal
+        );
+        // sessions can not end in a non-terminal state
+        assert_eq!(end_with_status(SessionStatus::Ok), SessionStatus::Exited);
+    }
+
+    #[test]
+    fn test_session_errored() {
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+            sentry::end_session();
+
+            sentry::start_session();
+            let err = "NaN".parse::<usize>().unwrap_err();
+            sentry::capture_error(&err);
+            sentry::end_session();
+        });
+
+        let sessions: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions[0].status, SessionStatus::Exited);
+        assert!(!sessions[0].is_errored());
+        // the update sent along with the error is already errored
+        assert_eq!(sessions[1].status, SessionStatus::Ok);
+        assert!(sessions[1].is_errored());
+        // a session that had errors exits normally, and remains errored
+        assert_eq!(sessions[2].status, SessionStatus::Exited);
+        assert_eq!(sessions[2].errors, 1);
+        assert!(sessions[2].is_errored());
+    }
+
+    #[test]
+    fn test_end_session_returns_closed() {
+        use crate::protocol::{Exception, Mechanism};
+
+        let envelopes = capture_envelopes(|| {
+            assert!(!sentry::end_session());
+
+            sentry::start_session();
+            assert!(sentry::end_session());
+            assert!(!sentry::end_session());
+
+            sentry::start_session();
+            sentry::capture_event(Event {
+                exception: vec![Exception {
+                    mechanism: Some(Mechanism {
+                        handled: Some(false),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }]
+                .into(),
+                ..Default::default()
+            });
+            assert!(!sentry::end_session());
+        });
+
+        let statuses: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session.status),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(statuses, [SessionStatus::Exited, SessionStatus::Crashed]);
+    }
+
+    #[test]
+    fn test_session_tracking_trait() {
+        use crate::{Hub, SessionTracking};
+
+        fn track<S: SessionTracking>(sessions: &S) -> bool {
+            sessions.start_session();
+            sessions.update_session_from_event(&Event {
+                level: Level::Error,
+                ..Default::default()
+            });
+            sessions.end_session()
+        }
+
+        let envelopes = capture_envelopes(|| assert!(track(&*Hub::current())));
+        assert_eq!(envelopes.len(), 1);
+        match envelopes[0].items().next() {
+            Some(EnvelopeItem::SessionUpdate(session)) => {
+                assert_eq!(session.status, SessionStatus::Exited);
+                assert_eq!(session.errors, 1);
+            }
+            _ => panic!("expected session"),
+        }
+    }
+
+    #[test]
+    fn test_end_session_at() {
+        let end_session_at = |offset| {
+            let envelopes = capture_envelopes(|| {
+                sentry::start_session();
+                let started = sentry::current_session().unwrap().started;
+                assert!(sentry::end_session_at(started + offset));
+            });
+            assert_eq!(envelopes.len(), 1);
+            match envelopes[0].items().next() {
+                Some(EnvelopeItem::SessionUpdate(session)) => {
+                    assert_eq!(session.status, SessionStatus::Exited);
+                    (
+                        session.duration,
+                        session.timestamp.unwrap() - session.started,
+                    )
+                }
+                _ => panic!("expected session"),
+            }
+        };
+
+        let (duration, elapsed) = end_session_at(chrono::Duration::milliseconds(1500));
+        assert_eq!(duration, Some(1.5));
+        assert_eq!(elapsed, chrono::Duration::milliseconds(1500));
+
+        // an end before the start is clamped to a duration of zero
+        let (duration, elapsed) = end_session_at(chrono::Duration::seconds(-10));
+        assert_eq!(duration, Some(0.0));
+        assert_eq!(elapsed, chrono::Duration::zero());
+    }
+
+    #[test]
+    fn test_session_deferred_until_release() {
+        let transport = crate::test::TestTransport::new();
+        let client = |release: Option<&'static str>| {
+            Some(Arc::new(Client::from(crate::ClientOptions {
+                dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+                release: release.map(Into::into),
+                transport: Some(Arc::new(transport.clone())),
+                ..Default::default()
+            })))
+        };
+
+        let hub = Arc::new(sentry::Hub::new(client(None), Default::default()));
+        sentry::Hub::run(hub.clone(), || {
+            sentry::start_session();
+            assert!(sentry::current_session().is_none());
+
+            // the session is started once the release is known
+            sentry::Hub::current().bind_client(client(Some("some-release")));
+            assert!(sentry::current_session().is_some());
+            assert!(sentry::end_session());
+
+            // ending a deferred session cancels it
+            sentry::Hub::current().bind_client(client(None));
+            sentry::start_session();
+            sentry::end_session();
+            sentry::Hub::current().bind_client(client(Some("some-release")));
+            assert!(sentry::current_session().is_none());
+        });
+
+        let envelopes = transport.fetch_and_clear_envelopes();
+        assert_eq!(envelopes.len(), 1);
+        match envelopes[0].items().next() {
+            Some(EnvelopeItem::SessionUpdate(session)) => {
+                assert_eq!(session.attributes.release, "some-release");
+                assert_eq!(session.status, SessionStatus::Exited);
+            }
+            _ => panic!("expected session"),
+        }
+    }
+
+    #[test]
+    fn test_session_background_timeout() {
+        let clock = crate::test::TestClock::new();
+        let mut session_ids = vec![];
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+                session_ids.push(sentry::current_session().unwrap().session_id);
+
+                // returning within the timeout continues the session
+                sentry::session_did_enter_background();
+                clock.advance(Duration::from_secs(10));
+                assert!(!sentry::session_will_enter_foreground());
+                assert_eq!(
+                    sentry::current_session().unwrap().session_id,
+                    session_ids[0]
+                );
+
+                // returning after the timeout starts a new session
+                sentry::session_did_enter_background();
+                clock.advance(Duration::from_secs(31));
+                assert!(sentry::session_will_enter_foreground());
+                session_ids.push(sentry::current_session().unwrap().session_id);
+                assert_ne!(session_ids[0], session_ids[1]);
+
+                // without entering the background, nothing changes
+                assert!(!sentry::session_will_enter_foreground());
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                session_clock: Some(clock.clone()),
+                ..Default::default()
+            },
+        );
+
+        let updates: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(updates.len(), 2);
+        // the first session ended when the application entered the background
+        assert_eq!(updates[0].session_id, session_ids[0]);
+        assert_eq!(updates[0].status, SessionStatus::Exited);
+        assert_eq!(updates[0].duration, Some(10.0));
+        assert_eq!(updates[1].session_id, session_ids[1]);
+    }
+
+    #[test]
+    fn test_attributes_from_options() {
+        let attributes = attributes_from_options(&crate::ClientOptions {
+            release: Some("some-release".into()),
+            environment: Some("production".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(attributes.release, "some-release");
+        assert_eq!(attributes.environment.as_deref(), Some("production"));
+        assert_eq!(attributes.ip_address, None);
+        assert_eq!(attributes.user_agent, None);
+        assert!(attributes.tags.is_empty());
+
+        assert_eq!(attributes_from_options(&Default::default()), None);
+        let options = crate::ClientOptions {
+            require_release_for_sessions: false,
+            ..Default::default()
+        };
+        let attributes = attributes_from_options(&options).unwrap();
+        assert_eq!(attributes.release, FALLBACK_RELEASE);
+        assert_eq!(attributes.environment, options.environment);
+    }
+
+    #[test]
+    fn test_max_session_duration() {
+        let clock = crate::test::TestClock::new();
+        let mut session_ids = vec![];
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+                session_ids.push(sentry::current_session().unwrap().session_id);
+
+                clock.advance(Duration::from_secs(3599));
+                sentry::capture_message("within the session", Level::Info);
+                assert_eq!(
+                    sentry::current_session().unwrap().session_id,
+                    session_ids[0]
+                );
+
+                // the next event after the maximum duration rolls the session
+                clock.advance(Duration::from_secs(1));
+                sentry::capture_message("in a new session", Level::Error);
+                let session = sentry::current_session().unwrap();
+                assert_ne!(session.session_id, session_ids[0]);
+                assert_eq!(session.errors, 1);
+                session_ids.push(session.session_id);
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                session_clock: Some(clock.clone()),
+                max_session_duration: Some(Duration::from_secs(3600)),
+                ..Default::default()
+            },
+        );
+
+        let updates: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(updates.len(), 4);
+        // the first event carries the initial update of the first session
+        assert_eq!(updates[0].session_id, session_ids[0]);
+        assert_eq!(updates[0].status, SessionStatus::Ok);
+        assert_eq!(updates[1].session_id, session_ids[0]);
+        assert_eq!(updates[1].status, SessionStatus::Exited);
+        assert_eq!(updates[1].duration, Some(3600.0));
+        assert_eq!(updates[1].errors, 0);
+        // the error is sent along with the event, and the new session is
+        // closed when the hub goes away
+        assert_eq!(updates[2].session_id, session_ids[1]);
+        assert_eq!(updates[2].errors, 1);
+        assert!(updates[2].init);
+        assert_eq!(updates[3].session_id, session_ids[1]);
+        assert_eq!(updates[3].status, SessionStatus::Exited);
+    }
+
+    #[test]
+    fn test_max_session_duration_outside_of_lock() {
+        // a transport that accesses the hub for session envelopes, which
+        // deadlocks when they are sent while the stack lock is held
+        struct HubTransport(Arc<crate::test::TestTransport>);
+
+        impl crate::Transport for HubTransport {
+            fn send_envelope(&self, envelope: Envelope) {
+                if envelope.event().is_none() {
+                    sentry::configure_scope(|scope| scope.set_tag("sent", "yes"));
+                }
+                self.0.send_envelope(envelope);
+            }
+        }
+
+        let clock = crate::test::TestClock::new();
+        let transport = crate::test::TestTransport::new();
+        let options = crate::ClientOptions {
+            dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+            release: Some("some-release".into()),
+            transport: Some(Arc::new(Arc::new(HubTransport(transport.clone())))),
+            session_clock: Some(clock.clone()),
+            max_session_duration: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let hub = Arc::new(sentry::Hub::new(
+            Some(Arc::new(options.into())),
+            Default::default(),
+        ));
+
+        sentry::Hub::run(hub, || {
+            sentry::start_session();
+            let session_id = sentry::current_session().unwrap().session_id;
+            clock.advance(Duration::from_secs(3600));
+            sentry::capture_message("in a new session", Level::Info);
+            assert_ne!(sentry::current_session().unwrap().session_id, session_id);
+        });
+
+        let envelopes = transport.fetch_and_clear_envelopes();
+        match envelopes[0].items().next() {
+            Some(EnvelopeItem::SessionUpdate(session)) => {
+                assert_eq!(session.status, SessionStatus::Exited);
+            }
+            _ => panic!("expected the expired session"),
+        }
+    }
+
+    #[test]
+    fn test_max_session_duration_heartbeat() {
+        let clock = crate::test::TestClock::new();
+        let mut session_ids = vec![];
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+                session_ids.push(sentry::current_session().unwrap().session_id);
+
+                clock.advance(Duration::from_secs(3599));
+                assert!(sentry::session_heartbeat());
+                assert_eq!(
+                    sentry::current_session().unwrap().session_id,
+                    session_ids[0]
+                );
+
+                // the next heartbeat after the maximum duration rolls the session
+                clock.advance(Duration::from_secs(1));
+                assert!(sentry::session_heartbeat());
+                let session = sentry::current_session().unwrap();
+                assert_ne!(session.session_id, session_ids[0]);
+                session_ids.push(session.session_id);
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                session_clock: Some(clock.clone()),
+                max_session_duration: Some(Duration::from_secs(3600)),
+                ..Default::default()
+            },
+        );
+
+        let updates: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session.clone()),
+                _ => None,
             })
-            .unwrap();
+            .collect();
+        assert_eq!(updates.len(), 4);
+        assert_eq!(updates[0].session_id, session_ids[0]);
+        assert_eq!(updates[0].status, SessionStatus::Ok);
+        assert!(updates[0].init);
+        assert_eq!(updates[1].session_id, session_ids[0]);
+        assert_eq!(updates[1].status, SessionStatus::Exited);
+        assert_eq!(updates[1].duration, Some(3600.0));
+        assert_eq!(updates[2].session_id, session_ids[1]);
+        assert_eq!(updates[2].status, SessionStatus::Ok);
+        assert!(updates[2].init);
+        assert_eq!(updates[3].session_id, session_ids[1]);
+        assert_eq!(updates[3].status, SessionStatus::Exited);
+    }
 
-        Self {
-            transport,
-            queue,
-            shutdown,
-            worker: Some(worker),
-        }
+    #[test]
+    fn test_end_session_and_start_new() {
+        let mut session_ids = vec![];
+        let envelopes = capture_envelopes(|| {
+            let hub = sentry::Hub::current();
+            assert!(!hub.end_session_and_start_new());
+            session_ids.push(sentry::current_session().unwrap().session_id);
+            assert!(hub.end_session_and_start_new());
+            session_ids.push(sentry::current_session().unwrap().session_id);
+        });
+        assert_ne!(session_ids[0], session_ids[1]);
+
+        let updates: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session.clone()),
+                _ => None,
+            })
+            .collect();
+        // the first session was closed when the second one was started
+        assert_eq!(updates[0].session_id, session_ids[0]);
+        assert_eq!(updates[0].status, SessionStatus::Exited);
+        assert!(updates[0].duration.is_some());
+        assert!(updates.len() > 1);
+        assert!(updates
+            .iter()
+            .skip(1)
+            .all(|session| session.session_id == session_ids[1]));
     }
 
-    /// Enqueues a session update for delayed sending.
-    ///
-    /// When the queue is full, it will be flushed immediately.
-    pub fn enqueue(&self, session_update: SessionUpdate<'static>) {
-        let mut queue = self.queue.lock().unwrap();
-        queue.push(session_update);
-        if queue.len() >= MAX_SESSION_ITEMS {
-            SessionFlusher::flush(queue, &self.transport);
+    #[test]
+    fn test_session_sample_rate() {
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                for _ in 0..1000 {
+                    sentry::start_session();
+                    let err = "NaN".parse::<usize>().unwrap_err();
+                    sentry::capture_error(&err);
+                }
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                session_sample_rate: 0.5,
+                ..Default::default()
+            },
+        );
+
+        let (mut events, mut sessions) = (0, 0);
+        for item in envelopes.iter().flat_map(|envelope| envelope.items()) {
+            match item {
+                EnvelopeItem::Event(_) => events += 1,
+                EnvelopeItem::SessionUpdate(session) if session.init => sessions += 1,
+                _ => {}
+            }
         }
+        // events are not affected by the session sample rate
+        assert_eq!(events, 1000);
+        assert!(sessions > 400 && sessions < 600, "{} sessions", sessions);
     }
 
-    /// Flushes the queue to the transport.
-    ///
-    /// This is a static method as it will be called from both the background
-    /// thread and the main thread on drop.
-    fn flush(mut queue_lock: MutexGuard<Vec<SessionUpdate<'static>>>, transport: &TransportArc) {
-        let queue: Vec<_> = std::mem::take(queue_lock.as_mut());
-        drop(queue_lock);
+    #[test]
+    fn test_sampled_out_session_replaces_previous() {
+        let transport = crate::test::TestTransport::new();
+        let client = |session_sample_rate| {
+            let options = crate::ClientOptions {
+                dsn: Some("https://public@sentry.invalid/1".parse().unwrap()),
+                release: Some("some-release".into()),
+                transport: Some(Arc::new(transport.clone())),
+                session_sample_rate,
+                ..Default::default()
+            };
+            Arc::new(crate::Client::from(options))
+        };
+        let hub = Arc::new(sentry::Hub::new(Some(client(1.0)), Default::default()));
 
-        if queue.is_empty() {
-            return;
-        }
+        let session_id = sentry::Hub::run(hub, || {
+            sentry::start_session();
+            let session_id = sentry::current_session().unwrap().session_id;
+            sentry::Hub::current().bind_client(Some(client(0.0)));
+            // the new session is sampled out, but the previous one is still closed
+            sentry::start_session();
+            assert!(sentry::current_session().is_none());
+            session_id
+        });
 
-        let mut envelope = Envelope::new();
-        let mut items = 0;
+        let updates: Vec<_> = transport
+            .fetch_and_clear_envelopes()
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].session_id, session_id);
+        assert_eq!(updates[0].status, SessionStatus::Exited);
+    }
 
-        for session_update in queue {
-            if items >= MAX_SESSION_ITEMS {
-                if let Some(ref transport) = *transport.read().unwrap() {
-                    transport.send_envelope(envelope);
-                }
-                envelope = Envelope::new();
-                items = 0;
-            }
-            envelope.add_item(session_update);
-            items += 1;
-        }
+    #[test]
+    fn test_session_update_timestamp() {
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+            std::thread::sleep(Duration::from_millis(20));
+            let err = "NaN".parse::<usize>().unwrap_err();
+            sentry::capture_error(&err);
+        });
+        assert_eq!(envelopes.len(), 2);
 
-        if let Some(ref transport) = *transport.read().unwrap() {
-            transport.send_envelope(envelope);
+        let updates: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(updates.len(), 2);
+        for update in updates {
+            let timestamp = update.timestamp.unwrap();
+            assert!(timestamp > update.started);
         }
     }
-}
 
-impl Drop for SessionFlusher {
-    fn drop(&mut self) {
-        let (lock, cvar) = self.shutdown.as_ref();
-        *lock.lock().unwrap() = true;
-        cvar.notify_one();
+    #[test]
+    fn test_session_sequence() {
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+            let err = "NaN".parse::<usize>().unwrap_err();
+            sentry::capture_error(&err);
+            sentry::capture_error(&err);
+            sentry::end_session();
+        });
 
-        if let Some(worker) = self.worker.take() {
-            worker.join().ok();
-        }
-        SessionFlusher::flush(self.queue.lock().unwrap(), &self.transport);
+        let sequences: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session.sequence),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sequences, vec![Some(0), Some(1), Some(2)]);
     }
-}
 
-#[cfg(all(test, feature = "test"))]
-mod tests {
-    use super::*;
-    use crate as sentry;
-    use crate::protocol::{Envelope, EnvelopeItem, SessionStatus};
+    #[test]
+    fn test_session_sequence_saturates() {
+        let mut session_update = SessionUpdate::builder("some-release").build();
+        advance_sequence(&mut session_update);
+        assert_eq!(session_update.sequence, Some(0));
 
-    fn capture_envelopes<F>(f: F) -> Vec<Envelope>
-    where
-        F: FnOnce(),
-    {
-        crate::test::with_captured_envelopes_options(
-            f,
+        session_update.sequence = Some(u64::MAX - 1);
+        advance_sequence(&mut session_update);
+        assert_eq!(session_update.sequence, Some(u64::MAX));
+        advance_sequence(&mut session_update);
+        assert_eq!(session_update.sequence, Some(u64::MAX));
+    }
+
+    #[test]
+    fn test_session_tags() {
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::configure_scope(|scope| {
+                    scope.set_tag("datacenter", "eu");
+                    scope.set_tag("feature_flag", "on");
+                    scope.set_tag("zone", "b");
+                });
+                sentry::start_session();
+            },
             crate::ClientOptions {
                 release: Some("some-release".into()),
+                max_session_tags: 2,
                 ..Default::default()
             },
-        )
+        );
+        assert_eq!(envelopes.len(), 1);
+
+        let mut body = Vec::new();
+        envelopes[0].to_writer(&mut body).unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains(r#""tags":{"datacenter":"eu","feature_flag":"on"}"#));
+        assert!(!body.contains("zone"));
     }
 
     #[test]
-    fn test_session_startstop() {
+    fn test_session_max_errors() {
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                sentry::start_session();
+                for _ in 0..10 {
+                    sentry::capture_message("oh no", sentry::Level::Error);
+                }
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                max_session_errors: 3,
+                ..Default::default()
+            },
+        );
+        assert_eq!(envelopes.len(), 11);
+
+        let errors: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session.errors),
+                _ => None,
+            })
+            .collect();
+        // the error count saturates at the cap
+        assert_eq!(errors, vec![1, 2, 3, 3]);
+    }
+
+    #[test]
+    fn test_session_error() {
         let envelopes = capture_envelopes(|| {
             sentry::start_session();
-            std::thread::sleep(std::time::Duration::from_millis(10));
+
+            let err = "NaN".parse::<usize>().unwrap_err();
+            sentry::capture_error(&err);
         });
-        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes.len(), 2);
 
         let mut items = envelopes[0].items();
+        assert!(matches!(items.next(), Some(EnvelopeItem::Event(_))));
         if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
-            assert_eq!(session.status, SessionStatus::Exited);
-            assert!(session.duration.unwrap() > 0.01);
-            assert_eq!(session.errors, 0);
+            assert_eq!(session.status, SessionStatus::Ok);
+            assert_eq!(session.errors, 1);
             assert_eq!(session.attributes.release, "some-release");
             assert_eq!(session.init, true);
         } else {
             panic!("expected session");
         }
         assert_eq!(items.next(), None);
+
+        let mut items = envelopes[1].items();
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Exited);
+            assert_eq!(session.errors, 1);
+            assert_eq!(session.init, false);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
     }
 
     #[test]
-    fn test_session_batching() {
-        #![allow(clippy::match_like_matches_macro)]
-        let envelopes = capture_envelopes(|| {
-            for _ in 0..(MAX_SESSION_ITEMS * 2) {
+    fn test_session_distinct_id_hasher() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        let hash = |distinct_id: &str| {
+            let mut hasher = DefaultHasher::new();
+            distinct_id.hash(&mut hasher);
+            format!("{:016x}", hasher.finish())
+        };
+        let set_user = |id: &str| {
+            let user = User {
+                id: Some(id.into()),
+                ..Default::default()
+            };
+            sentry::configure_scope(|scope| scope.set_user(Some(user)));
+        };
+
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                set_user("some-user");
+                sentry::start_session();
+                sentry::end_session();
                 sentry::start_session();
+                sentry::end_session();
+                sentry::start_session();
+                set_user("other-user");
+                sentry::end_session();
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                session_distinct_id_hasher: Some(Arc::new(hash)),
+                ..Default::default()
+            },
+        );
+
+        let distinct_ids: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => session.distinct_id.clone(),
+                _ => None,
+            })
+            .collect();
+        // sessions of the same user share the same hashed id
+        assert_eq!(
+            distinct_ids,
+            [hash("some-user"), hash("some-user"), hash("other-user")]
+        );
+    }
+
+    #[test]
+    fn test_session_distinct_id_normalization() {
+        let distinct_ids = |normalize_session_distinct_ids| {
+            let envelopes = crate::test::with_captured_envelopes_options(
+                || {
+                    for email in &["User@Example.com", " user@example.com", "USER@example.COM"] {
+                        let user = User {
+                            email: Some((*email).into()),
+                            ..Default::default()
+                        };
+                        sentry::configure_scope(|scope| scope.set_user(Some(user)));
+                        sentry::start_session();
+                        sentry::end_session();
+                    }
+                    let user = User {
+                        id: Some(" Some-User ".into()),
+                        ..Default::default()
+                    };
+                    sentry::configure_scope(|scope| scope.set_user(Some(user)));
+                    sentry::start_session();
+                    sentry::end_session();
+                },
+                crate::ClientOptions {
+                    release: Some("some-release".into()),
+                    normalize_session_distinct_ids,
+                    ..Default::default()
+                },
+            );
+            envelopes
+                .iter()
+                .flat_map(|envelope| envelope.items())
+                .filter_map(|item| match item {
+                    EnvelopeItem::SessionUpdate(session) => session.distinct_id.clone(),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            distinct_ids(false),
+            [
+                "User@Example.com",
+                " user@example.com",
+                "USER@example.COM",
+                " Some-User "
+            ]
+        );
+        // emails fold into a single user, while other ids keep their case
+        assert_eq!(
+            distinct_ids(true),
+            [
+                "user@example.com",
+                "user@example.com",
+                "user@example.com",
+                "Some-User"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_session_distinct_id_source() {
+        use crate::DistinctIdSource::*;
+
+        let distinct_id = |session_distinct_id_source| {
+            let envelopes = crate::test::with_captured_envelopes_options(
+                || {
+                    let user = User {
+                        id: Some("some-id".into()),
+                        email: Some("user@example.com".into()),
+                        username: Some("some-username".into()),
+                        ..Default::default()
+                    };
+                    sentry::configure_scope(|scope| scope.set_user(Some(user)));
+                    sentry::start_session();
+                    sentry::end_session();
+                },
+                crate::ClientOptions {
+                    release: Some("some-release".into()),
+                    session_distinct_id_source,
+                    ..Default::default()
+                },
+            );
+            match envelopes[0].items().next() {
+                Some(EnvelopeItem::SessionUpdate(session)) => session.distinct_id.clone(),
+                _ => panic!("expected session"),
             }
-        });
-        // we only want *two* envelope for all the sessions
-        assert_eq!(envelopes.len(), 2);
+        };
 
-        let items = envelopes[0].items().chain(envelopes[1].items());
-        assert_eq!(items.clone().count(), MAX_SESSION_ITEMS * 2);
-        for item in items {
-            assert!(match item {
-                EnvelopeItem::SessionUpdate(_) => true,
-                _ => false,
-            });
-        }
+        assert_eq!(
+            distinct_id(crate::ClientOptions::default().session_distinct_id_source),
+            Some("some-id".into())
+        );
+        assert_eq!(
+            distinct_id(vec![Email, Id, Username]),
+            Some("user@example.com".into())
+        );
+        assert_eq!(distinct_id(vec![Username]), Some("some-username".into()));
+        assert_eq!(distinct_id(vec![]), None);
     }
 
     #[test]
-    fn test_session_error() {
+    fn test_session_distinct_id_change() {
         let envelopes = capture_envelopes(|| {
             sentry::start_session();
 
             let err = "NaN".parse::<usize>().unwrap_err();
             sentry::capture_error(&err);
+
+            sentry::configure_scope(|scope| {
+                scope.set_user(Some(sentry::User {
+                    id: Some("some-user".into()),
+                    ..Default::default()
+                }))
+            });
         });
         assert_eq!(envelopes.len(), 2);
 
@@ -310,9 +5439,7 @@ mod tests {
         assert!(matches!(items.next(), Some(EnvelopeItem::Event(_))));
         if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
             assert_eq!(session.status, SessionStatus::Ok);
-            assert_eq!(session.errors, 1);
-            assert_eq!(session.attributes.release, "some-release");
-            assert_eq!(session.init, true);
+            assert_eq!(session.distinct_id, None);
         } else {
             panic!("expected session");
         }
@@ -321,8 +5448,86 @@ mod tests {
         let mut items = envelopes[1].items();
         if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
             assert_eq!(session.status, SessionStatus::Exited);
-            assert_eq!(session.errors, 1);
-            assert_eq!(session.init, false);
+            assert_eq!(session.distinct_id.as_deref(), Some("some-user"));
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
+    #[test]
+    fn test_session_heartbeat() {
+        let clock = crate::test::TestClock::new();
+        let envelopes = crate::test::with_captured_envelopes_options(
+            || {
+                assert!(!sentry::session_heartbeat());
+                sentry::start_session();
+                clock.advance(Duration::from_secs(60));
+                assert!(sentry::session_heartbeat());
+                clock.advance(Duration::from_secs(60));
+                assert!(sentry::session_heartbeat());
+                sentry::end_session();
+                // ended sessions ignore heartbeats
+                assert!(!sentry::session_heartbeat());
+            },
+            crate::ClientOptions {
+                release: Some("some-release".into()),
+                session_clock: Some(clock.clone()),
+                ..Default::default()
+            },
+        );
+
+        let updates: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(updates.len(), 3);
+        let statuses: Vec<_> = updates.iter().map(|update| update.status).collect();
+        assert_eq!(
+            statuses,
+            [SessionStatus::Ok, SessionStatus::Ok, SessionStatus::Exited]
+        );
+        let sequences: Vec<_> = updates.iter().map(|update| update.sequence).collect();
+        assert_eq!(sequences, [Some(0), Some(1), Some(2)]);
+        assert!(updates[0].init);
+        assert!(!updates[1].init);
+        let started = updates[0].started;
+        assert_eq!(
+            updates[0].timestamp,
+            add_duration(started, Duration::from_secs(60))
+        );
+        assert_eq!(
+            updates[1].timestamp,
+            add_duration(started, Duration::from_secs(120))
+        );
+        assert_eq!(updates[2].duration, Some(120.0));
+    }
+
+    #[test]
+    fn test_session_abnormal() {
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+            sentry::mark_session_abnormal();
+
+            // the session is terminal, and does not count this error
+            let err = "NaN".parse::<usize>().unwrap_err();
+            sentry::capture_error(&err);
+
+            sentry::end_session();
+        });
+        assert_eq!(envelopes.len(), 1);
+
+        let mut items = envelopes[0].items();
+        assert!(matches!(items.next(), Some(EnvelopeItem::Event(_))));
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Abnormal);
+            assert!(session.duration.is_some());
+            assert_eq!(session.errors, 0);
+            assert!(session.init);
         } else {
             panic!("expected session");
         }
@@ -360,6 +5565,40 @@ mod tests {
         assert_eq!(items.next(), None);
     }
 
+    #[test]
+    fn test_session_multiple_exceptions() {
+        use crate::protocol::{Exception, Mechanism};
+
+        let exception = |handled| Exception {
+            ty: "Error".into(),
+            mechanism: Some(Mechanism {
+                handled,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+
+            let event = Event {
+                exception: vec![exception(Some(true)), exception(Some(false))].into(),
+                ..Default::default()
+            };
+            sentry::capture_event(event);
+        });
+        assert_eq!(envelopes.len(), 1);
+
+        let mut items = envelopes[0].items();
+        assert!(matches!(items.next(), Some(EnvelopeItem::Event(_))));
+        if let Some(EnvelopeItem::SessionUpdate(session)) = items.next() {
+            assert_eq!(session.status, SessionStatus::Crashed);
+            assert_eq!(session.errors, 1);
+        } else {
+            panic!("expected session");
+        }
+        assert_eq!(items.next(), None);
+    }
+
     /// For _user-mode_ sessions, we want to inherit the session for any _new_
     /// Hub that is spawned from the main thread Hub which already has a session
     /// attached
@@ -401,6 +5640,37 @@ mod tests {
         assert_eq!(items.next(), None);
     }
 
+    #[test]
+    fn test_inherit_session_single_init() {
+        let envelopes = capture_envelopes(|| {
+            sentry::start_session();
+
+            let hubs: Vec<_> = (0..3)
+                .map(|_| std::sync::Arc::new(sentry::Hub::new_from_top(sentry::Hub::current())))
+                .collect();
+            for hub in hubs {
+                sentry::Hub::run(hub, || {
+                    let err = "NaN".parse::<usize>().unwrap_err();
+                    sentry::capture_error(&err);
+                });
+            }
+        });
+        assert_eq!(envelopes.len(), 4); // 3 errors and one session end
+
+        let sessions: Vec<_> = envelopes
+            .iter()
+            .flat_map(|envelope| envelope.items())
+            .filter_map(|item| match item {
+                EnvelopeItem::SessionUpdate(session) => Some(session),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sessions.len(), 4);
+        assert_eq!(sessions.iter().filter(|session| session.init).count(), 1);
+        assert!(sessions[0].init);
+        assert_eq!(sessions[3].errors, 3);
+    }
+
     /// We want to forward-inherit sessions as the previous test asserted, but
     /// not *backwards*. So any new session created in a derived Hub and scope
     /// will only get updates from that particular scope.
@@ -0,0 +1,93 @@
+use crate::protocol::Event;
+use crate::Hub;
+
+/// Release Health Session tracking abstraction.
+///
+/// This allows framework integrations to manage sessions without reaching
+/// into the internals of the [`Hub`], and to be tested against a mock
+/// implementation instead.  The global [`start_session`] and
+/// [`end_session`] functions are thin wrappers around the implementation for
+/// the current [`Hub`].
+///
+/// # Examples
+///
+/// ```
+/// use std::cell::Cell;
+///
+/// use sentry::protocol::Event;
+/// use sentry::{Hub, SessionTracking};
+///
+/// // A request handler of some framework integration.
+/// fn handle_request<S: SessionTracking>(sessions: &S, handler: impl FnOnce()) {
+///     sessions.start_session();
+///     handler();
+///     sessions.end_session();
+/// }
+///
+/// // The integration can run against the current `Hub`...
+/// handle_request(&*Hub::current(), || {});
+///
+/// // ...or be tested against a mock.
+/// #[derive(Default)]
+/// struct MockSessions {
+///     started: Cell<usize>,
+///     ended: Cell<usize>,
+/// }
+///
+/// impl SessionTracking for MockSessions {
+///     fn start_session(&self) {
+///         self.started.set(self.started.get() + 1);
+///     }
+///     fn end_session(&self) -> bool {
+///         self.ended.set(self.ended.get() + 1);
+///         true
+///     }
+///     fn update_session_from_event(&self, _event: &Event<'static>) {}
+/// }
+///
+/// let mock = MockSessions::default();
+/// handle_request(&mock, || {});
+/// assert_eq!(mock.started.get(), 1);
+/// assert_eq!(mock.ended.get(), 1);
+/// ```
+///
+/// [`Hub`]: struct.Hub.html
+/// [`start_session`]: fn.start_session.html
+/// [`end_session`]: fn.end_session.html
+pub trait SessionTracking {
+    /// Starts a new session, replacing the current one.
+    fn start_session(&self);
+
+    /// Ends the current session.
+    ///
+    /// Returns `true` if a session was active and has been closed.
+    fn end_session(&self) -> bool;
+
+    /// Updates the current session from an `event`.
+    ///
+    /// This increments the error count of the session, or marks it as
+    /// crashed, depending on the event.  Events captured through the
+    /// [`Hub`] already update the session, so this is only needed for events
+    /// that are handled outside of it.
+    ///
+    /// [`Hub`]: struct.Hub.html
+    fn update_session_from_event(&self, event: &Event<'static>);
+}
+
+impl SessionTracking for Hub {
+    fn start_session(&self) {
+        Hub::start_session(self)
+    }
+
+    fn end_session(&self) -> bool {
+        Hub::end_session(self)
+    }
+
+    fn update_session_from_event(&self, event: &Event<'static>) {
+        with_client_impl! {{
+            self.inner.with(|stack| {
+                stack.top().scope.update_session_from_event(event);
+            })
+        }}
+    }
+}
@@ -20,10 +20,11 @@
 //! ```
 
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use crate::protocol::Event;
-use crate::types::Dsn;
-use crate::{ClientOptions, Envelope, Hub, Transport};
+use crate::protocol::{EnvelopeItem, Event};
+use crate::types::{DateTime, Dsn, Utc};
+use crate::{Client, ClientOptions, Clock, Envelope, Hub, Transport};
 
 lazy_static::lazy_static! {
     static ref TEST_DSN: Dsn = "https://public@sentry.invalid/1".parse().unwrap();
@@ -80,6 +81,56 @@ impl Transport for TestTransport {
     }
 }
 
+/// A clock that only moves forward when advanced manually.
+///
+/// # Examples
+///
+/// ```
+/// use sentry::test::TestClock;
+/// use sentry::ClientOptions;
+/// use std::time::Duration;
+///
+/// let clock = TestClock::new();
+/// let options = ClientOptions {
+///     session_clock: Some(clock.clone()),
+///     ..ClientOptions::default()
+/// };
+/// clock.advance(Duration::from_secs(1));
+/// ```
+pub struct TestClock {
+    instant: Instant,
+    utc: DateTime<Utc>,
+    elapsed: Mutex<Duration>,
+}
+
+impl TestClock {
+    /// Creates a new test clock, starting at the current time.
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> Arc<TestClock> {
+        Arc::new(TestClock {
+            instant: Instant::now(),
+            utc: Utc::now(),
+            elapsed: Mutex::new(Duration::default()),
+        })
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for TestClock {
+    fn now_instant(&self) -> Instant {
+        self.instant + *self.elapsed.lock().unwrap()
+    }
+
+    fn now_utc(&self) -> DateTime<Utc> {
+        let elapsed = *self.elapsed.lock().unwrap();
+        crate::session::add_duration(self.utc, elapsed).unwrap()
+    }
+}
+
 /// Runs some code with the default test hub and returns the captured events.
 ///
 /// See [`with_captured_envelopes_options`](fn.with_captured_envelopes_options.html)
@@ -134,3 +185,64 @@ pub fn with_captured_envelopes_options<F: FnOnce(), O: Into<ClientOptions>>(
     );
     transport.fetch_and_clear_envelopes()
 }
+
+/// Runs some code with the default test hub and returns the envelopes with
+/// session updates.
+///
+/// See [`flush_and_capture_sessions_options`](fn.flush_and_capture_sessions_options.html)
+pub fn flush_and_capture_sessions<F: FnOnce()>(f: F) -> Vec<Envelope> {
+    flush_and_capture_sessions_options(f, ClientOptions::default())
+}
+
+/// Runs some code with the default test hub with the given options and
+/// returns the envelopes with session updates.
+///
+/// This works like
+/// [`with_captured_envelopes_options`](fn.with_captured_envelopes_options.html),
+/// but once the code has run, all the session updates are explicitly flushed
+/// instead of relying on the client being dropped.  Only the envelopes that
+/// contain at least one session update are returned.  As sessions require a
+/// release by default, a test release is inserted if none is set.
+///
+/// # Examples
+///
+/// ```
+/// use sentry::protocol::{EnvelopeItem, SessionStatus};
+///
+/// let envelopes = sentry::test::flush_and_capture_sessions(|| {
+///     sentry::start_session();
+/// });
+/// assert_eq!(envelopes.len(), 1);
+/// match envelopes[0].items().next() {
+///     Some(EnvelopeItem::SessionUpdate(session)) => {
+///         assert_eq!(session.status, SessionStatus::Exited);
+///     }
+///     _ => panic!("expected session"),
+/// }
+/// ```
+pub fn flush_and_capture_sessions_options<F: FnOnce(), O: Into<ClientOptions>>(
+    f: F,
+    options: O,
+) -> Vec<Envelope> {
+    let transport = TestTransport::new();
+    let mut options = options.into();
+    options.dsn = Some(options.dsn.unwrap_or_else(|| TEST_DSN.clone()));
+    options.release = Some(options.release.unwrap_or_else(|| "test-release".into()));
+    options.transport = Some(Arc::new(transport.clone()));
+    let client = Arc::new(Client::from(options));
+    // the hub is dropped right after the call, which ends the current session
+    Hub::run(
+        Arc::new(Hub::new(Some(client.clone()), Arc::new(Default::default()))),
+        f,
+    );
+    client.flush_sessions();
+    transport
+        .fetch_and_clear_envelopes()
+        .into_iter()
+        .filter(|envelope| {
+            envelope
+                .items()
+                .any(|item| matches!(item, EnvelopeItem::SessionUpdate(_)))
+        })
+        .collect()
+}
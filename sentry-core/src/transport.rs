@@ -14,6 +14,29 @@ pub trait Transport: Send + Sync + 'static {
     /// [`Envelope`]: struct.Envelope.html
     fn send_envelope(&self, envelope: Envelope);
 
+    /// Tries to send an [`Envelope`], handing it back if it could not be queued.
+    ///
+    /// This is used for data that can be retried later, such as session updates,
+    /// so that it is not lost when the transport is applying backpressure.
+    /// The default implementation calls `send_envelope` and always succeeds.
+    ///
+    /// [`Envelope`]: struct.Envelope.html
+    fn try_send_envelope(&self, envelope: Envelope) -> Result<(), Envelope> {
+        self.send_envelope(envelope);
+        Ok(())
+    }
+
+    /// Returns `true` if the transport compresses the envelopes it sends.
+    ///
+    /// The SDK uses this to avoid compressing envelopes twice, and to tell
+    /// whether `session_envelope_compression` has any effect.  Transports
+    /// that compress request bodies on their own, for instance through a
+    /// compressing HTTP client, should return `true`.  The default
+    /// implementation returns `false`.
+    fn compresses_envelopes(&self) -> bool {
+        false
+    }
+
     /// Drains the queue if there is one.
     ///
     /// The default implementation does nothing.  If the queue was successfully
@@ -57,6 +80,14 @@ impl<T: Transport> Transport for Arc<T> {
         (**self).send_envelope(envelope)
     }
 
+    fn try_send_envelope(&self, envelope: Envelope) -> Result<(), Envelope> {
+        (**self).try_send_envelope(envelope)
+    }
+
+    fn compresses_envelopes(&self) -> bool {
+        (**self).compresses_envelopes()
+    }
+
     fn shutdown(&self, timeout: Duration) -> bool {
         (**self).shutdown(timeout)
     }
@@ -174,6 +174,53 @@ impl Envelope {
     }
 }
 
+/// Serializes a [`SessionUpdate`] as an Envelope Item and parses it back.
+///
+/// The update is written through [`Envelope::to_writer`], after which the
+/// item header is checked for the `session` type and the correct `length`
+/// before the payload is deserialized again. This is meant to be used as a
+/// fuzz target and in property tests to catch serialization mismatches.
+///
+/// This is only available with the `fuzzing` feature.
+///
+/// [`SessionUpdate`]: struct.SessionUpdate.html
+/// [`Envelope::to_writer`]: struct.Envelope.html#method.to_writer
+#[cfg(any(test, feature = "fuzzing"))]
+pub fn session_update_roundtrip(
+    update: &SessionUpdate<'static>,
+) -> Result<SessionUpdate<'static>, serde_json::Error> {
+    use serde::de::Error;
+
+    #[derive(serde::Deserialize)]
+    struct ItemHeader {
+        #[serde(rename = "type")]
+        ty: String,
+        length: usize,
+    }
+
+    let mut envelope = Envelope::new();
+    envelope.add_item(update.clone());
+    let mut buf = Vec::new();
+    envelope
+        .to_writer(&mut buf)
+        .map_err(serde_json::Error::custom)?;
+
+    let mut lines = buf.splitn(3, |b| *b == b'\n');
+    let _envelope_header = lines.next();
+    let header: ItemHeader = serde_json::from_slice(lines.next().unwrap_or_default())?;
+    if header.ty != "session" {
+        return Err(serde_json::Error::custom(format!(
+            "unexpected item type `{}`",
+            header.ty
+        )));
+    }
+    let rest = lines.next().unwrap_or_default();
+    if rest.len() != header.length + 1 || rest[header.length] != b'\n' {
+        return Err(serde_json::Error::custom("item length mismatch"));
+    }
+    serde_json::from_slice(&rest[..header.length])
+}
+
 impl From<Event<'static>> for Envelope {
     fn from(event: Event<'static>) -> Self {
         let mut envelope = Self::default();
@@ -192,7 +239,10 @@ impl From<Transaction<'static>> for Envelope {
 
 #[cfg(test)]
 mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
     use chrono::{DateTime, Utc};
+    use proptest::prelude::*;
 
     use super::*;
     use crate::protocol::v7::{SessionAttributes, SessionStatus, Span};
@@ -246,6 +296,7 @@ mod test {
                 environment: Some("production".into()),
                 ip_address: None,
                 user_agent: None,
+                tags: Default::default(),
             },
         };
         let mut envelope = Envelope::new();
@@ -286,4 +337,88 @@ mod test {
 "#
         )
     }
+
+    fn from_millis(ms: u64) -> DateTime<Utc> {
+        (UNIX_EPOCH + Duration::from_millis(ms)).into()
+    }
+
+    fn arb_session_update() -> impl Strategy<Value = SessionUpdate<'static>> {
+        let status = prop_oneof![
+            Just(SessionStatus::Ok),
+            Just(SessionStatus::Exited),
+            Just(SessionStatus::Crashed),
+            Just(SessionStatus::Abnormal),
+        ];
+        let attributes = (
+            "[a-z0-9@.-]{1,20}",
+            proptest::option::of("[a-z]{1,10}"),
+            proptest::collection::btree_map("[a-z]{1,8}", "[ -~]{0,16}", 0..4),
+        )
+            .prop_map(|(release, environment, tags)| SessionAttributes {
+                release: release.into(),
+                environment: environment.map(Into::into),
+                ip_address: None,
+                user_agent: None,
+                tags,
+            });
+        (
+            any::<u128>(),
+            proptest::option::of("[ -~]{0,20}"),
+            proptest::option::of(any::<u64>()),
+            proptest::option::of(0u64..4_000_000_000_000),
+            0u64..4_000_000_000_000,
+            any::<bool>(),
+            proptest::option::of(
+                proptest::num::f64::POSITIVE
+                    | proptest::num::f64::NEGATIVE
+                    | proptest::num::f64::NORMAL
+                    | proptest::num::f64::SUBNORMAL
+                    | proptest::num::f64::ZERO,
+            ),
+            status,
+            any::<u64>(),
+            attributes,
+        )
+            .prop_map(
+                |(
+                    sid,
+                    did,
+                    sequence,
+                    timestamp,
+                    started,
+                    init,
+                    duration,
+                    status,
+                    errors,
+                    attributes,
+                )| {
+                    SessionUpdate {
+                        session_id: Uuid::from_u128(sid),
+                        distinct_id: did,
+                        sequence,
+                        timestamp: timestamp.map(from_millis),
+                        started: from_millis(started),
+                        init,
+                        duration,
+                        status,
+                        errors,
+                        attributes,
+                    }
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn test_session_roundtrip(update in arb_session_update()) {
+            let mut parsed = session_update_roundtrip(&update).unwrap();
+            // without `float_roundtrip`, the duration may be off in its last digits
+            if let (Some(parsed_duration), Some(duration)) = (parsed.duration, update.duration) {
+                let tolerance = 4.0 * f64::EPSILON * duration.abs().max(f64::MIN_POSITIVE);
+                prop_assert!((parsed_duration - duration).abs() <= tolerance);
+                parsed.duration = update.duration;
+            }
+            prop_assert_eq!(parsed, update);
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::net::IpAddr;
 use std::str;
@@ -30,6 +31,19 @@ impl Default for SessionStatus {
     }
 }
 
+impl SessionStatus {
+    /// Returns whether this is a terminal status.
+    ///
+    /// Only `Ok` sessions are still ongoing.  A session in any other status
+    /// has ended, and must not transition to another status anymore.
+    pub fn is_terminal(self) -> bool {
+        match self {
+            SessionStatus::Ok => false,
+            SessionStatus::Exited | SessionStatus::Crashed | SessionStatus::Abnormal => true,
+        }
+    }
+}
+
 /// An error used when parsing `SessionStatus`.
 #[derive(Debug, Error)]
 #[error("invalid session status")]
@@ -61,7 +75,11 @@ impl fmt::Display for SessionStatus {
 }
 
 /// Additional attributes for Sessions.
+///
+/// New attributes may be added in the future, so use `SessionAttributes::new`
+/// to create them.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct SessionAttributes<'a> {
     /// The release version string.
     pub release: Cow<'a, str>,
@@ -77,6 +95,29 @@ pub struct SessionAttributes<'a> {
     /// The user agent of the user. This data is not persisted but used for filtering.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub user_agent: Option<String>,
+
+    /// A limited set of custom tags of the session.
+    ///
+    /// These are not part of the documented session protocol, and are only
+    /// considered by servers that support them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl<'a> SessionAttributes<'a> {
+    /// Creates the attributes of a session of the given `release`.
+    pub fn new<R>(release: R) -> Self
+    where
+        R: Into<Cow<'a, str>>,
+    {
+        SessionAttributes {
+            release: release.into(),
+            environment: None,
+            ip_address: None,
+            user_agent: None,
+            tags: BTreeMap::new(),
+        }
+    }
 }
 
 #[allow(clippy::trivially_copy_pass_by_ref)]
@@ -99,6 +140,9 @@ pub struct SessionUpdate<'a> {
     pub distinct_id: Option<String>,
 
     /// An optional logical clock.
+    ///
+    /// The server treats this as monotonically increasing per session, and
+    /// discards updates with a lower number than one it has already seen.
     #[serde(rename = "seq", default, skip_serializing_if = "Option::is_none")]
     pub sequence: Option<u64>,
 
@@ -130,3 +174,116 @@ pub struct SessionUpdate<'a> {
     #[serde(rename = "attrs")]
     pub attributes: SessionAttributes<'a>,
 }
+
+impl<'a> SessionUpdate<'a> {
+    /// Creates a builder for a new session of the given `release`.
+    ///
+    /// The session gets a new random `session_id`, and is marked as the
+    /// initial transmission of the session, unless an existing `session_id`
+    /// is provided.
+    pub fn builder<R>(release: R) -> SessionUpdateBuilder<'a>
+    where
+        R: Into<Cow<'a, str>>,
+    {
+        SessionUpdateBuilder {
+            update: SessionUpdate {
+                session_id: Uuid::new_v4(),
+                distinct_id: None,
+                sequence: None,
+                timestamp: None,
+                started: Utc::now(),
+                init: true,
+                duration: None,
+                status: SessionStatus::Ok,
+                errors: 0,
+                attributes: SessionAttributes {
+                    release: release.into(),
+                    environment: None,
+                    ip_address: None,
+                    user_agent: None,
+                    tags: BTreeMap::new(),
+                },
+            },
+        }
+    }
+
+    /// Returns whether the session has ended.
+    ///
+    /// See [`SessionStatus::is_terminal`](enum.SessionStatus.html#method.is_terminal).
+    pub fn is_terminal(&self) -> bool {
+        self.status.is_terminal()
+    }
+
+    /// Returns whether the session is counted as errored by the server.
+    ///
+    /// The protocol has no dedicated status for sessions that ended with
+    /// errors.  Such sessions are reported as `Exited` with a non-zero
+    /// `errors` count instead.  Crashed and abnormal sessions are always
+    /// considered errored, independently of their `errors` count.
+    pub fn is_errored(&self) -> bool {
+        match self.status {
+            SessionStatus::Crashed | SessionStatus::Abnormal => true,
+            SessionStatus::Ok | SessionStatus::Exited => self.errors > 0,
+        }
+    }
+}
+
+/// A builder for manually created `SessionUpdate`s.
+///
+/// See [`SessionUpdate::builder`](struct.SessionUpdate.html#method.builder).
+#[derive(Clone, Debug)]
+pub struct SessionUpdateBuilder<'a> {
+    update: SessionUpdate<'a>,
+}
+
+impl<'a> SessionUpdateBuilder<'a> {
+    /// Sets the id of an existing session, which this is a subsequent update of.
+    ///
+    /// This also clears the `init` flag, as the session was already sent before.
+    pub fn session_id(mut self, session_id: Uuid) -> Self {
+        self.update.session_id = session_id;
+        self.update.init = false;
+        self
+    }
+
+    /// Sets the distinct identifier of the session.
+    pub fn distinct_id<S: Into<String>>(mut self, distinct_id: S) -> Self {
+        self.update.distinct_id = Some(distinct_id.into());
+        self
+    }
+
+    /// Sets the environment of the session.
+    pub fn environment<E: Into<Cow<'a, str>>>(mut self, environment: E) -> Self {
+        self.update.attributes.environment = Some(environment.into());
+        self
+    }
+
+    /// Sets the status of the session.
+    pub fn status(mut self, status: SessionStatus) -> Self {
+        self.update.status = status;
+        self
+    }
+
+    /// Sets the number of errors that occurred in the session.
+    pub fn errors(mut self, errors: u64) -> Self {
+        self.update.errors = errors;
+        self
+    }
+
+    /// Sets the duration of the session in seconds.
+    pub fn duration(mut self, duration: f64) -> Self {
+        self.update.duration = Some(duration);
+        self
+    }
+
+    /// Sets the timestamp of when the session started.
+    pub fn started(mut self, started: DateTime<Utc>) -> Self {
+        self.update.started = started;
+        self
+    }
+
+    /// Builds the `SessionUpdate`.
+    pub fn build(self) -> SessionUpdate<'a> {
+        self.update
+    }
+}
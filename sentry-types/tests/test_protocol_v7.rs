@@ -1430,3 +1430,18 @@ fn test_orientation() {
         "\"portrait\""
     );
 }
+
+#[test]
+fn test_session_status_terminal() {
+    assert!(!v7::SessionStatus::Ok.is_terminal());
+    assert!(v7::SessionStatus::Exited.is_terminal());
+    assert!(v7::SessionStatus::Crashed.is_terminal());
+    assert!(v7::SessionStatus::Abnormal.is_terminal());
+
+    let update = v7::SessionUpdate::builder("some-release").build();
+    assert!(!update.is_terminal());
+    let update = v7::SessionUpdate::builder("some-release")
+        .status(v7::SessionStatus::Exited)
+        .build();
+    assert!(update.is_terminal());
+}
@@ -1,11 +1,13 @@
 #![allow(unused_imports)]
 
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, SystemTime};
 
+#[cfg(any(feature = "reqwest", feature = "curl", feature = "surf"))]
+use flate2::{write::GzEncoder, Compression};
 #[cfg(any(feature = "reqwest", feature = "curl", feature = "surf"))]
 use httpdate::parse_http_date;
 
@@ -26,7 +28,7 @@ use surf_::Client as SurfClient;
 
 use sentry_core::sentry_debug;
 
-use crate::protocol::Event;
+use crate::protocol::{EnvelopeItem, Event};
 use crate::{ClientOptions, Envelope, Transport, TransportFactory};
 
 /// Creates the default HTTP transport.
@@ -62,6 +64,26 @@ fn parse_retry_after(s: &str) -> Option<SystemTime> {
     }
 }
 
+/// Serializes the `envelope` into a request body.
+///
+/// Envelopes that only consist of session updates are gzip-compressed when
+/// `compress_sessions` is enabled, in which case this returns `true` as well.
+#[cfg(any(feature = "reqwest", feature = "curl", feature = "surf"))]
+fn envelope_body(envelope: &Envelope, compress_sessions: bool) -> (Vec<u8>, bool) {
+    let only_sessions = envelope
+        .items()
+        .all(|item| matches!(item, EnvelopeItem::SessionUpdate(_)));
+    if compress_sessions && only_sessions {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        envelope.to_writer(&mut encoder).unwrap();
+        (encoder.finish().unwrap(), true)
+    } else {
+        let mut body = Vec::new();
+        envelope.to_writer(&mut body).unwrap();
+        (body, false)
+    }
+}
+
 #[allow(unused)]
 macro_rules! implement_http_transport {
     (
@@ -76,6 +98,7 @@ macro_rules! implement_http_transport {
             shutdown_signal: Arc<Condvar>,
             shutdown_immediately: Arc<AtomicBool>,
             queue_size: Arc<Mutex<usize>>,
+            compress_sessions: bool,
             handle: Option<JoinHandle<()>>,
         }
 
@@ -115,6 +138,7 @@ macro_rules! implement_http_transport {
                     shutdown_signal,
                     shutdown_immediately,
                     queue_size,
+                    compress_sessions: options.session_envelope_compression,
                     handle,
                 }
             }
@@ -122,15 +146,32 @@ macro_rules! implement_http_transport {
 
         impl Transport for $typename {
             fn send_envelope(&self, envelope: Envelope) {
+                self.try_send_envelope(envelope).ok();
+            }
+
+            fn try_send_envelope(&self, envelope: Envelope) -> Result<(), Envelope> {
                 // we count up before we put the item on the queue and in case the
                 // queue is filled with too many items or we shut down, we decrement
                 // the count again as there is nobody that can pick it up.
                 *self.queue_size.lock().unwrap() += 1;
-                if self.sender.lock().unwrap().try_send(Some(envelope)).is_err() {
-                    *self.queue_size.lock().unwrap() -= 1;
+                let result = self.sender.lock().unwrap().try_send(Some(envelope));
+                match result {
+                    Ok(()) => Ok(()),
+                    Err(err) => {
+                        *self.queue_size.lock().unwrap() -= 1;
+                        match err {
+                            TrySendError::Full(Some(envelope))
+                            | TrySendError::Disconnected(Some(envelope)) => Err(envelope),
+                            _ => Ok(()),
+                        }
+                    }
                 }
             }
 
+            fn compresses_envelopes(&self) -> bool {
+                self.compress_sessions
+            }
+
             fn shutdown(&self, timeout: Duration) -> bool {
                 sentry_debug!("shutting down http transport");
                 if *self.queue_size.lock().unwrap() == 0 {
@@ -184,6 +225,7 @@ implement_http_transport! {
     ) {
         let dsn = options.dsn.clone().unwrap();
         let user_agent = options.user_agent.to_string();
+        let compress_sessions = options.session_envelope_compression;
 
         let mut disabled = None::<SystemTime>;
         let http_proxy = options.http_proxy.as_ref().map(ToString::to_string);
@@ -228,16 +270,17 @@ implement_http_transport! {
                         }
                     }
 
-                    let mut body = Vec::new();
-                    envelope.to_writer(&mut body).unwrap();
+                    let (body, compressed) = envelope_body(&envelope, compress_sessions);
 
                     sentry_debug!("Sending envelope");
-                    match http_client
+                    let mut request = http_client
                         .post(url.as_str())
                         .body(body)
-                        .header("X-Sentry-Auth", dsn.to_auth(Some(&user_agent)).to_string())
-                        .send()
-                    {
+                        .header("X-Sentry-Auth", dsn.to_auth(Some(&user_agent)).to_string());
+                    if compressed {
+                        request = request.header("Content-Encoding", "gzip");
+                    }
+                    match request.send() {
                         Ok(resp) => {
                             if resp.status() == 429 {
                                 if let Some(retry_after) = resp
@@ -293,6 +336,7 @@ implement_http_transport! {
     ) {
         let dsn = options.dsn.clone().unwrap();
         let user_agent = options.user_agent.to_string();
+        let compress_sessions = options.session_envelope_compression;
         let http_proxy = options.http_proxy.as_ref().map(ToString::to_string);
         let https_proxy = options.https_proxy.as_ref().map(ToString::to_string);
 
@@ -339,8 +383,7 @@ implement_http_transport! {
                     _ => {}
                 }
 
-                let mut body = Vec::new();
-                envelope.to_writer(&mut body).unwrap();
+                let (body, compressed) = envelope_body(&envelope, compress_sessions);
                 let mut body = Cursor::new(body);
 
                 let mut retry_after = None;
@@ -348,6 +391,9 @@ implement_http_transport! {
                 headers.append(&format!("X-Sentry-Auth: {}", dsn.to_auth(Some(&user_agent)))).unwrap();
                 headers.append("Expect:").unwrap();
                 headers.append("Content-Type: application/json").unwrap();
+                if compressed {
+                    headers.append("Content-Encoding: gzip").unwrap();
+                }
                 handle.http_headers(headers).unwrap();
                 handle.upload(true).unwrap();
                 handle.in_filesize(body.get_ref().len() as u64).unwrap();
@@ -426,6 +472,7 @@ implement_http_transport! {
     ) {
         let dsn = options.dsn.clone().unwrap();
         let user_agent = options.user_agent.to_string();
+        let compress_sessions = options.session_envelope_compression;
         let mut disabled = None::<SystemTime>;
 
         thread::Builder::new()
@@ -457,16 +504,18 @@ implement_http_transport! {
                         }
                     }
 
-                    let mut body = Vec::new();
-                    envelope.to_writer(&mut body).unwrap();
+                    let (body, compressed) = envelope_body(&envelope, compress_sessions);
 
-                    let fut = http_client
+                    let mut fut = http_client
                         .post(url.as_str())
                         .header(
                             "X-Sentry-Auth",
                             dsn.to_auth(Some(&user_agent)).to_string()
                         )
                         .body(body);
+                    if compressed {
+                        fut = fut.header("Content-Encoding", "gzip");
+                    }
 
                     match executor::block_on(fut) {
                         Ok(resp) => {
@@ -515,3 +564,67 @@ type DefaultTransport = SurfHttpTransport;
 /// The default http transport.
 #[cfg(any(feature = "reqwest", feature = "curl", feature = "surf"))]
 pub type HttpTransport = DefaultTransport;
+
+#[cfg(all(test, feature = "reqwest"))]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::{envelope_body, HttpTransport};
+    use crate::protocol::{Event, SessionUpdate};
+    use crate::{ClientOptions, Envelope, Transport};
+
+    #[test]
+    fn test_compresses_envelopes() {
+        let dsn = "https://public@example.com/1".parse().ok();
+        let transport = HttpTransport::new(&ClientOptions {
+            dsn: dsn.clone(),
+            ..Default::default()
+        });
+        assert!(!transport.compresses_envelopes());
+
+        let transport = HttpTransport::new(&ClientOptions {
+            dsn,
+            session_envelope_compression: true,
+            ..Default::default()
+        });
+        assert!(transport.compresses_envelopes());
+    }
+
+    #[test]
+    fn test_session_envelope_compression() {
+        let mut envelope = Envelope::new();
+        envelope.add_item(SessionUpdate::builder("some-release").build());
+        let mut expected = Vec::new();
+        envelope.to_writer(&mut expected).unwrap();
+
+        let (body, compressed) = envelope_body(&envelope, false);
+        assert!(!compressed);
+        assert_eq!(body, expected);
+
+        let (body, compressed) = envelope_body(&envelope, true);
+        assert!(compressed);
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&body[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, expected);
+
+        // every line of the envelope is valid json
+        let text = String::from_utf8(decompressed).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for line in lines {
+            serde_json::from_str::<serde_json::Value>(line).unwrap();
+        }
+        let session: SessionUpdate = serde_json::from_str(text.lines().nth(2).unwrap()).unwrap();
+        assert_eq!(session.attributes.release, "some-release");
+
+        // envelopes with events are never compressed
+        let mut envelope = Envelope::new();
+        envelope.add_item(Event::default());
+        let (_, compressed) = envelope_body(&envelope, true);
+        assert!(!compressed);
+    }
+}
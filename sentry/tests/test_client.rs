@@ -56,3 +56,29 @@ fn test_unwind_safe() {
 
     assert_eq!(events.len(), 1);
 }
+
+#[test]
+fn test_auto_session_tracking() {
+    let transport = sentry::test::TestTransport::new();
+    let _guard = sentry::init(sentry::ClientOptions {
+        dsn: Some("https://public@example.com/1".parse().unwrap()),
+        release: Some("some-release".into()),
+        transport: Some(Arc::new(transport.clone())),
+        auto_session_tracking: true,
+        ..sentry::ClientOptions::default()
+    });
+
+    // the session was started without an explicit `start_session`
+    assert!(sentry::current_session().is_some());
+    sentry::end_session();
+
+    let envelopes = transport.fetch_and_clear_envelopes();
+    assert_eq!(envelopes.len(), 1);
+    match envelopes[0].items().next() {
+        Some(sentry::protocol::EnvelopeItem::SessionUpdate(session)) => {
+            assert!(session.init);
+            assert_eq!(session.status, sentry::protocol::SessionStatus::Exited);
+        }
+        _ => panic!("expected session"),
+    }
+}
@@ -0,0 +1,56 @@
+#![cfg(all(feature = "test", feature = "panic"))]
+
+use std::panic;
+use std::sync::Arc;
+
+use sentry::protocol::{EnvelopeItem, SessionStatus};
+
+#[test]
+fn test_panic_crashes_session() {
+    let mut crash_info = None;
+    let envelopes = sentry::test::with_captured_envelopes_options(
+        || {
+            sentry::start_session();
+            panic::catch_unwind(|| panic!("Oh no!")).unwrap_err();
+
+            let session = sentry::current_session().unwrap();
+            assert_eq!(session.status, SessionStatus::Crashed);
+            assert_eq!(session.errors, 1);
+            crash_info = session.crash_info;
+
+            // the crashed session does not receive any more updates
+            sentry::capture_message("after the crash", sentry::Level::Error);
+            let session = sentry::current_session().unwrap();
+            assert_eq!(session.status, SessionStatus::Crashed);
+            assert_eq!(session.errors, 1);
+        },
+        sentry::ClientOptions {
+            release: Some("some-release".into()),
+            integrations: vec![Arc::new(
+                sentry::integrations::panic::PanicIntegration::new(),
+            )],
+            ..Default::default()
+        },
+    );
+
+    let mut items = envelopes[0].items();
+    let event = match items.next() {
+        Some(EnvelopeItem::Event(event)) => event,
+        _ => panic!("expected event"),
+    };
+    assert_eq!(event.exception[0].ty, "panic");
+    assert_eq!(crash_info.unwrap().event_id, event.event_id);
+    match items.next() {
+        Some(EnvelopeItem::SessionUpdate(session)) => {
+            assert_eq!(session.status, SessionStatus::Crashed);
+            assert_eq!(session.errors, 1);
+        }
+        _ => panic!("expected session"),
+    }
+    assert_eq!(items.next(), None);
+
+    // the message is sent, but does not carry another session update
+    let mut items = envelopes[1].items();
+    assert!(matches!(items.next(), Some(EnvelopeItem::Event(_))));
+    assert_eq!(items.next(), None);
+}